@@ -0,0 +1,74 @@
+use crate::git::GitRepo;
+use anyhow::{anyhow, Context as _, Result};
+use git2::Commit;
+use log::{info, warn};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::SystemTime;
+
+/// Run command in a temporary directory containing the snapshot tree
+pub(crate) fn run_on_snapshot(repo: &GitRepo, commit: &Commit<'_>, cmd: &str) -> Result<bool> {
+    let dir = env::temp_dir().join(format!(
+        "autosave-snapshot-{}-{}",
+        std::process::id(),
+        commit.id()
+    ));
+    repo.restore_tree(commit, &[], Some(&dir), false, false)?;
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .current_dir(&dir)
+        .status()
+        .with_context(|| format!("Failed to run command: {}", cmd));
+    remove_dir(&dir);
+    let success = status?.success();
+    info!(
+        "{} {}",
+        commit.id(),
+        if success { "succeeded" } else { "failed" }
+    );
+    Ok(success)
+}
+
+fn remove_dir(dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(dir) {
+        warn!("Failed to remove {}: {}", dir.display(), e);
+    }
+}
+
+/// Find the first snapshot on specified branch where command fails
+///
+/// Returns `None` if the command succeeds on the latest snapshot
+pub fn bisect<'r>(
+    repo: &'r GitRepo,
+    branch: impl AsRef<str>,
+    cmd: impl AsRef<str>,
+    since: Option<SystemTime>,
+) -> Result<Option<Commit<'r>>> {
+    let cmd = cmd.as_ref();
+    let mut snapshots = repo.list_snapshots(branch, since)?;
+    let (Some(oldest), Some(latest)) = (snapshots.first(), snapshots.last()) else {
+        return Err(anyhow!("No snapshot found"));
+    };
+    if run_on_snapshot(repo, latest, cmd)? {
+        return Ok(None);
+    }
+    if !run_on_snapshot(repo, oldest, cmd)? {
+        return Err(anyhow!(
+            "Command fails on the oldest snapshot {}",
+            oldest.id()
+        ));
+    }
+    let (mut good, mut bad) = (0, snapshots.len() - 1);
+    while bad - good > 1 {
+        let mid = (good + bad) / 2;
+        if run_on_snapshot(repo, &snapshots[mid], cmd)? {
+            good = mid;
+        } else {
+            bad = mid;
+        }
+    }
+    Ok(Some(snapshots.swap_remove(bad)))
+}
@@ -1,16 +1,167 @@
-use anyhow::{Context as _, Result};
-use serde::Deserialize;
+use crate::time::Schedule;
+use crate::types::{RedactRule, SaveOptions};
+use anyhow::{anyhow, Context as _, Result};
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// Configuration object
 ///
 /// Config file is deserialized to this object
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     branch: Option<String>,
     commit_message: Option<String>,
     merge_message: Option<String>,
+    daily_summary: Option<bool>,
+    large_repo: Option<bool>,
+    save_rate: Option<f64>,
+    save_burst: Option<u32>,
+    missing_timeout: Option<String>,
+    save_timeout: Option<String>,
+    redact: Option<Vec<RedactConfig>>,
+    export_recipients: Option<Vec<String>>,
+    dry_run: Option<bool>,
+    notifications: Option<bool>,
+    schedule: Option<ScheduleConfig>,
+    commit_summary: Option<bool>,
+    max_commits_per_hour: Option<u32>,
+    trigger: Option<String>,
+    verify_command: Option<String>,
+    verify_interval: Option<String>,
+}
+
+/// User configuration applied to every repository
+///
+/// Read from `autosave/config.toml` in the user configuration directory,
+/// since a repository configuration must not be able to lift it
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    deny_paths: Option<Vec<PathBuf>>,
+    /// Directory of the file, which relative paths are resolved against
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+/// Schedule in config file, e.g. `{ days = ["mon-fri"], hours = "09:00-19:00" }`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduleConfig {
+    days: Option<Vec<String>>,
+    hours: Option<String>,
+}
+
+/// Redaction rule in config file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct RedactConfig {
+    glob: String,
+    pattern: String,
+    replace: String,
+}
+
+/// File events which request a save
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveTrigger {
+    /// Every change of file contents
+    Modify,
+    /// Files closed after writing, deletions and renames (inotify only)
+    CloseWrite,
+}
+
+/// Effective settings of a repository
+///
+/// Built once from configuration and shared by the watcher and the save worker
+#[derive(Debug, PartialEq)]
+pub struct Settings {
+    pub branch: String,
+    pub commit_message: String,
+    pub merge_message: String,
+    pub daily_summary: bool,
+    pub save_options: SaveOptions,
+    pub save_rate: Option<f64>,
+    pub save_burst: u32,
+    pub save_timeout: Option<Duration>,
+    pub dry_run: bool,
+    pub notifications: bool,
+    pub schedule: Option<Schedule>,
+    pub trigger: SaveTrigger,
+    pub verify_command: Option<String>,
+    pub verify_interval: Duration,
+}
+
+impl TryFrom<&Config> for Settings {
+    type Error = anyhow::Error;
+
+    fn try_from(conf: &Config) -> Result<Self> {
+        Ok(Self {
+            branch: conf.branch(),
+            commit_message: conf.commit_message(),
+            merge_message: conf.merge_message(),
+            daily_summary: conf.daily_summary(),
+            save_options: SaveOptions {
+                large_repo: conf.large_repo(),
+                redact: conf.redact()?,
+                commit_summary: conf.commit_summary(),
+                max_commits_per_hour: conf.max_commits_per_hour(),
+            },
+            save_rate: conf.save_rate(),
+            save_burst: conf.save_burst(),
+            save_timeout: conf.save_timeout()?,
+            dry_run: conf.dry_run(),
+            notifications: conf.notifications(),
+            schedule: conf.schedule()?,
+            trigger: conf.trigger()?,
+            verify_command: conf.verify_command(),
+            verify_interval: conf.verify_interval()?,
+        })
+    }
+}
+
+/// User configuration directory, `$XDG_CONFIG_HOME` or `~/.config`
+pub fn config_home() -> Result<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME").filter(|p| !p.is_empty()) {
+        Some(p) => Ok(PathBuf::from(p)),
+        None => env::var_os("HOME")
+            .map(|h| PathBuf::from(h).join(".config"))
+            .ok_or_else(|| anyhow!("HOME is not set")),
+    }
+}
+
+impl UserConfig {
+    /// Read user configuration file, default if it does not exist
+    pub fn load() -> Result<Self> {
+        let path = config_home()?.join("autosave/config.toml");
+        if path.is_file() {
+            Self::from_file_path(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+    pub fn from_file_path(p: impl AsRef<Path>) -> Result<Self> {
+        let p = p.as_ref();
+        let s = fs::read_to_string(p)
+            .with_context(|| format!("Config file read error: {}", p.display()))?;
+        let mut c: Self = toml::from_str(&s)
+            .with_context(|| format!("Config file format error: {}", p.display()))?;
+        c.dir = p.parent().unwrap_or(Path::new("")).to_path_buf();
+        Ok(c)
+    }
+    /// Paths `run` refuses to watch in addition to root and home directory
+    ///
+    /// Relative paths are resolved against the directory of the file
+    pub fn deny_paths(&self) -> Vec<PathBuf> {
+        self.deny_paths
+            .iter()
+            .flatten()
+            .map(|p| self.dir.join(p))
+            .collect()
+    }
 }
 
 impl Config {
@@ -25,8 +176,9 @@ impl Config {
         let f = file_name.as_ref();
         loop {
             let file_path = path.join(f);
-            if let Ok(c) = Self::from_file_path(file_path) {
-                return Ok(c);
+            // Invalid config file is reported instead of falling back to defaults
+            if file_path.is_file() {
+                return Self::from_file_path(file_path);
             }
             if let Some(new_path) = path.parent() {
                 path = new_path.to_path_buf();
@@ -36,6 +188,41 @@ impl Config {
         }
     }
 
+    /// Configuration with every value checked and defaults filled in
+    ///
+    /// Values which are unset by default, like `save_rate`, stay unset
+    pub fn effective(&self) -> Result<Self> {
+        Settings::try_from(self)?;
+        let format = |d: Duration| humantime::format_duration(d).to_string();
+        Ok(Self {
+            branch: Some(self.branch()),
+            commit_message: Some(self.commit_message()),
+            merge_message: Some(self.merge_message()),
+            daily_summary: Some(self.daily_summary()),
+            large_repo: Some(self.large_repo()),
+            save_rate: self.save_rate(),
+            save_burst: Some(self.save_burst()),
+            missing_timeout: self.missing_timeout()?.map(format),
+            save_timeout: Some(self.save_timeout()?.map_or("0s".to_string(), format)),
+            redact: Some(self.redact.clone().unwrap_or_default()),
+            export_recipients: Some(self.export_recipients()),
+            dry_run: Some(self.dry_run()),
+            notifications: Some(self.notifications()),
+            schedule: self.schedule.clone(),
+            commit_summary: Some(self.commit_summary()),
+            max_commits_per_hour: self.max_commits_per_hour(),
+            trigger: Some(
+                match self.trigger()? {
+                    SaveTrigger::Modify => "modify",
+                    SaveTrigger::CloseWrite => "close_write",
+                }
+                .to_string(),
+            ),
+            verify_command: self.verify_command(),
+            verify_interval: Some(format(self.verify_interval()?)),
+        })
+    }
+
     /// Get branch name
     pub fn branch(&self) -> String {
         self.branch.clone().unwrap_or("tmp/autosave".to_string())
@@ -46,10 +233,156 @@ impl Config {
             .clone()
             .unwrap_or("auto save".to_string())
     }
-    /// Get merge message
+    /// Get merge message, which may contain `{files_changed}`, `{source_ref}` and `{target_branch}`
     pub fn merge_message(&self) -> String {
         self.merge_message
             .clone()
             .unwrap_or("auto merge".to_string())
     }
+    /// Whether daily summary is written
+    pub fn daily_summary(&self) -> bool {
+        self.daily_summary.unwrap_or(false)
+    }
+    /// Whether changed files are listed in commit messages
+    pub fn commit_summary(&self) -> bool {
+        self.commit_summary.unwrap_or(false)
+    }
+    /// Number of commits per hour after which the latest autosave commit is amended instead
+    pub fn max_commits_per_hour(&self) -> Option<u32> {
+        self.max_commits_per_hour.filter(|m| 0 < *m)
+    }
+    /// Whether large repository mode is enabled
+    pub fn large_repo(&self) -> bool {
+        self.large_repo.unwrap_or(false)
+    }
+    /// Maximum number of saves per minute, unlimited if not set
+    pub fn save_rate(&self) -> Option<f64> {
+        self.save_rate.filter(|r| 0.0 < *r)
+    }
+    /// Number of saves allowed in a burst when rate limited
+    pub fn save_burst(&self) -> u32 {
+        self.save_burst.unwrap_or(5).max(1)
+    }
+    /// How long watched path may be missing before the runner exits, e.g. "10m"
+    ///
+    /// The runner waits for the path to come back if not set
+    pub fn missing_timeout(&self) -> Result<Option<Duration>> {
+        self.missing_timeout
+            .as_ref()
+            .map(|s| {
+                humantime::parse_duration(s)
+                    .map_err(|e| anyhow!("Invalid missing_timeout: {}: {}", s, e))
+            })
+            .transpose()
+    }
+    /// Rules rewriting file contents in snapshots
+    pub fn redact(&self) -> Result<Vec<RedactRule>> {
+        self.redact
+            .iter()
+            .flatten()
+            .map(|r| {
+                Ok(RedactRule {
+                    glob: r.glob.clone(),
+                    pattern: Regex::new(&r.pattern)
+                        .with_context(|| format!("Invalid redact pattern: {}", r.pattern))?,
+                    replace: r.replace.clone(),
+                })
+            })
+            .collect()
+    }
+    /// gpg recipients exported bundles are encrypted for, not encrypted if empty
+    pub fn export_recipients(&self) -> Vec<String> {
+        self.export_recipients.clone().unwrap_or_default()
+    }
+    /// Whether saves only log files they would commit
+    pub fn dry_run(&self) -> bool {
+        self.dry_run.unwrap_or(false)
+    }
+    /// Whether desktop notifications are shown on the first save and on errors
+    pub fn notifications(&self) -> bool {
+        self.notifications.unwrap_or(true)
+    }
+    /// Window in local time in which saving is allowed, always allowed if not set
+    pub fn schedule(&self) -> Result<Option<Schedule>> {
+        self.schedule
+            .as_ref()
+            .map(|s| {
+                Schedule::parse(s.days.as_deref(), s.hours.as_deref()).context("Invalid schedule")
+            })
+            .transpose()
+    }
+    /// File events which request a save, "modify" by default or "close_write"
+    pub fn trigger(&self) -> Result<SaveTrigger> {
+        match self.trigger.as_deref() {
+            None | Some("modify") => Ok(SaveTrigger::Modify),
+            Some("close_write") => Ok(SaveTrigger::CloseWrite),
+            Some(t) => Err(anyhow!("Invalid trigger: {}", t)),
+        }
+    }
+    /// Command run on snapshots after saves, labeling them with the result
+    pub fn verify_command(&self) -> Option<String> {
+        self.verify_command.clone().filter(|c| !c.trim().is_empty())
+    }
+    /// Minimum interval of running verify command, 5 minutes by default
+    pub fn verify_interval(&self) -> Result<Duration> {
+        let Some(s) = self.verify_interval.as_ref() else {
+            return Ok(Duration::from_secs(5 * 60));
+        };
+        humantime::parse_duration(s).map_err(|e| anyhow!("Invalid verify_interval: {}: {}", s, e))
+    }
+    /// Time limit of a save, 10 minutes by default and disabled by "0s"
+    pub fn save_timeout(&self) -> Result<Option<Duration>> {
+        let Some(s) = self.save_timeout.as_ref() else {
+            return Ok(Some(Duration::from_secs(10 * 60)));
+        };
+        let timeout = humantime::parse_duration(s)
+            .map_err(|e| anyhow!("Invalid save_timeout: {}: {}", s, e))?;
+        Ok(Some(timeout).filter(|t| !t.is_zero()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<Config>("brnach = \"x\"").is_err());
+        assert!(toml::from_str::<Config>("schedule = { day = [\"mon\"] }").is_err());
+    }
+
+    #[test]
+    fn effective_config_has_same_settings() {
+        let conf: Config = toml::from_str(
+            "save_rate = 2.0\n\
+             save_timeout = \"0s\"\n\
+             schedule = { hours = \"22:00-06:00\" }\n\
+             [[redact]]\n\
+             glob = \"*.env\"\n\
+             pattern = \"KEY=.*\"\n\
+             replace = \"KEY=\"\n",
+        )
+        .unwrap();
+        let effective = toml::to_string(&conf.effective().unwrap()).unwrap();
+        let reparsed: Config = toml::from_str(&effective).unwrap();
+        assert_eq!(
+            Settings::try_from(&reparsed).unwrap(),
+            Settings::try_from(&conf).unwrap()
+        );
+        assert!(effective.contains("branch = \"tmp/autosave\""));
+    }
+
+    #[test]
+    fn resolves_deny_paths_against_user_config() {
+        let dir = env::temp_dir().join(format!("autosave-test-user-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "deny_paths = [\"/srv\", \"../Documents\"]\n").unwrap();
+        let conf = UserConfig::from_file_path(&path);
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(
+            conf.unwrap().deny_paths(),
+            [PathBuf::from("/srv"), dir.join("../Documents")]
+        );
+    }
 }
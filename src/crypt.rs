@@ -0,0 +1,78 @@
+use anyhow::{anyhow, Context as _, Result};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read as _, Write};
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Write output of `write` to file encrypted with gpg for recipients
+pub fn encrypt_to<T>(
+    path: &Path,
+    recipients: &[String],
+    write: impl FnOnce(&mut dyn Write) -> Result<T>,
+) -> Result<T> {
+    let mut cmd = Command::new("gpg");
+    cmd.args(["--batch", "--yes", "--encrypt", "--output"])
+        .arg(path);
+    for r in recipients {
+        cmd.arg("--recipient").arg(r);
+    }
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Failed to run gpg")?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let written = write(&mut stdin);
+    // gpg finishes when input is closed
+    drop(stdin);
+    let status = child.wait().context("Failed to run gpg")?;
+    let value = written?;
+    if !status.success() {
+        return Err(anyhow!("gpg failed to encrypt: {}", status));
+    }
+    Ok(value)
+}
+
+/// Whether file is not a plain git bundle, so it needs decryption
+pub fn is_encrypted(path: &Path) -> Result<bool> {
+    let mut head = [0; 3];
+    let n = File::open(path)
+        .and_then(|f| f.take(3).read(&mut head))
+        .with_context(|| format!("Failed to open file: {}", path.display()))?;
+    Ok(&head[..n] != b"# v")
+}
+
+/// Pass contents of file decrypted with gpg to `read`
+///
+/// Contents are decrypted to a temporary file first, so `read` only runs after gpg
+/// verified the whole file
+pub fn decrypt_from<T>(path: &Path, read: impl FnOnce(&mut dyn BufRead) -> Result<T>) -> Result<T> {
+    let tmp = env::temp_dir().join(format!("autosave-decrypted-{}", std::process::id()));
+    let result = decrypt_to(path, &tmp).and_then(|()| {
+        let file = File::open(&tmp).context("Failed to open decrypted file")?;
+        read(&mut BufReader::new(file))
+    });
+    let _ = fs::remove_file(&tmp);
+    result
+}
+
+fn decrypt_to(path: &Path, output: &Path) -> Result<()> {
+    // Decrypted contents are readable only by the user
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(output)
+        .with_context(|| format!("Failed to create file: {}", output.display()))?;
+    let status = Command::new("gpg")
+        .args(["--batch", "--decrypt"])
+        .arg(path)
+        .stdout(file)
+        .status()
+        .context("Failed to run gpg")?;
+    if !status.success() {
+        return Err(anyhow!("gpg failed to decrypt: {}", status));
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use crate::config::{Config, Settings};
+use crate::git::GitRepo;
+use crate::types::{PlannedFile, Snapshot, Watch};
+use crate::watcher::RepoWatcher;
+use anyhow::{Context as _, Result};
+use git2::Commit;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Autosave engine of a repository
+///
+/// Watches a path, saves changes and queries snapshots without the command line interface
+pub struct AutosaveEngine {
+    path: PathBuf,
+    settings: Arc<Settings>,
+    repo: GitRepo,
+}
+
+impl AutosaveEngine {
+    /// Open repository in specified path with `.autosave.toml` found in it or its parents
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conf = Config::from_dir_path(path.as_ref(), ".autosave.toml")?;
+        Self::with_config(path, conf)
+    }
+
+    /// Open repository in specified path with specified configuration
+    pub fn with_config(path: impl AsRef<Path>, conf: Config) -> Result<Self> {
+        let path = fs::canonicalize(path).context("Failed to get absolute path")?;
+        let repo = GitRepo::new(&path)
+            .with_context(|| format!("Not a git repository root: {}", path.display()))?;
+        Ok(Self {
+            path,
+            settings: Arc::new(Settings::try_from(&conf)?),
+            repo,
+        })
+    }
+
+    /// Absolute path of the working directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Start watching the repository
+    ///
+    /// Changes are saved until the returned watch is dropped
+    pub fn watch(&self) -> Result<Watch> {
+        RepoWatcher::with_settings(&self.path, self.settings.clone()).map(Watch::new)
+    }
+
+    /// Save whole working directory now
+    pub fn save(&self) -> Result<()> {
+        self.repo.save(
+            &self.settings.branch,
+            &self.settings.commit_message,
+            &self.settings.merge_message,
+            None,
+            &self.settings.save_options,
+        )
+    }
+
+    /// List files which `save` would commit or exclude, without saving
+    pub fn plan_save(&self) -> Result<Vec<PlannedFile>> {
+        self.repo
+            .plan_save(&self.settings.branch, &self.settings.save_options)
+    }
+
+    /// List snapshots from oldest to newest, optionally only after specified time
+    pub fn snapshots(&self, since: Option<SystemTime>) -> Result<Vec<Snapshot>> {
+        let commits = self.repo.list_snapshots(&self.settings.branch, since)?;
+        Ok(commits.iter().map(to_snapshot).collect())
+    }
+
+    /// Find the latest snapshot at specified time
+    pub fn snapshot_at(&self, at: SystemTime) -> Result<Option<Snapshot>> {
+        let commit = self.repo.find_snapshot_at(&self.settings.branch, at)?;
+        Ok(commit.as_ref().map(to_snapshot))
+    }
+}
+
+fn to_snapshot(commit: &Commit<'_>) -> Snapshot {
+    Snapshot {
+        id: commit.id(),
+        time: commit.time(),
+        message: commit.message().unwrap_or_default().to_string(),
+    }
+}
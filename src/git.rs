@@ -1,18 +1,69 @@
+use crate::time::from_git_time;
+use crate::types::{
+    BlameLine, FileChange, FileStat, LineMatch, PlannedFile, RedactRule, SaveOptions, SavePhase,
+    SaveProgress, SaveState, SaveStatus, Summary,
+};
 use anyhow::{anyhow, Context as _, Result};
 use git2::{
-    self, Branch, BranchType, Commit, Diff, DiffOptions, ErrorCode, Index, IndexAddOption,
-    IndexEntry, Oid, Reference, Repository, RepositoryState, ResetType,
+    self, build::CheckoutBuilder, BlameOptions, Branch, BranchType, Commit, Delta, Diff,
+    DiffFindOptions, DiffFormat, DiffOptions, ErrorClass, ErrorCode, Index, IndexAddOption,
+    IndexEntry, ObjectType, Oid, Patch, Pathspec, PathspecFlags, Reference, Repository,
+    RepositoryState, ResetType, Sort, StatusOptions, Tree,
 };
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum GitError {
-    #[error("Repository not found: {:?}", .0)]
+    #[error("Not a git repository: {}\nhint: run in a repository or pass its path", .0.message())]
     NoRepository(git2::Error),
-    #[error("Unknown error: {:?}", .0)]
+    #[error("Repository is locked: {}\nhint: wait for the running git command, or remove the lock file if none is running", .0.display())]
+    Locked(PathBuf),
+    #[error("Branch {} is checked out in another worktree: {}\nhint: configure another branch for this worktree", .0, .1.display())]
+    CheckedOut(String, PathBuf),
+    #[error("Repository is in the middle of {0:?}\nhint: changes are saved after it is finished or aborted")]
+    InProgress(RepositoryState),
+    #[error("{}{}", .0.message(), hint(.0))]
     Unknown(git2::Error),
 }
 
+/// Hint line for libgit2 errors with a known remedy
+fn hint(e: &git2::Error) -> &'static str {
+    let message = e.message();
+    if e.class() == ErrorClass::Config
+        && (message.contains("user.name") || message.contains("user.email"))
+    {
+        "\nhint: configure user.name and user.email with git config"
+    } else if e.code() == ErrorCode::Locked {
+        "\nhint: wait for the running git command, or remove the lock file if none is running"
+    } else if message.contains("No space left on device") {
+        "\nhint: free up disk space"
+    } else {
+        ""
+    }
+}
+
+/// Whether error is caused by a lock held by another git process
+pub fn is_lock_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| match e.downcast_ref::<GitError>() {
+        Some(GitError::Locked(_)) => true,
+        Some(GitError::Unknown(e)) => e.code() == ErrorCode::Locked,
+        _ => false,
+    })
+}
+
+/// Whether error is caused by a merge, rebase or other operation in progress
+pub fn is_in_progress_error(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|e| matches!(e.downcast_ref::<GitError>(), Some(GitError::InProgress(_))))
+}
+
 /// Reference name object
 #[derive(Debug)]
 pub enum ReferenceName {
@@ -20,23 +71,247 @@ pub enum ReferenceName {
     Commit(Oid),
 }
 
+/// Notes reference storing snapshot labels
+const LABELS_REF: &str = "refs/notes/autosave-labels";
+/// Notes reference storing daily summaries
+const SUMMARY_REF: &str = "refs/notes/autosave-summary";
+/// Marker file in git directory which pauses saving
+const PAUSE_FILE: &str = "autosave-paused";
+/// Pause marker older than this is ignored, e.g. left by an aborted rebase
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// State file in git directory written by the watcher
+const STATE_FILE: &str = "autosave-state";
+/// Format version of the state file
+const STATE_VERSION: &str = "1";
+/// Lock file in git directory held while the autosave branch is updated
+const LOCK_FILE: &str = "autosave.lock";
+/// Progress file in git directory written while saving whole working directory
+const PROGRESS_FILE: &str = "autosave-progress";
+/// Maximum number of files listed in commit message summary
+const SUMMARY_MAX_FILES: usize = 20;
+/// Minimum interval of progress file updates
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Hooks installed by `install_hooks`, which call `HOOK_SCRIPT` with their name
+const HOOKS: [&str; 5] = [
+    "pre-rebase",
+    "pre-merge-commit",
+    "post-rewrite",
+    "post-merge",
+    "post-checkout",
+];
+/// Script in hooks directory which pauses or resumes saving
+const HOOK_SCRIPT: &str = "autosave-hook";
+/// Shells which can run the line calling `HOOK_SCRIPT` from an existing hook
+const HOOK_SHELLS: [&str; 6] = ["sh", "bash", "dash", "ash", "ksh", "zsh"];
+
+/// Fill placeholders of merge message template
+///
+/// `{files_changed}` is the number of files changed on `source_ref` since the last merge,
+/// `{source_ref}` the merged branch and `{target_branch}` the autosave branch
+fn render_merge_message(
+    template: &str,
+    files_changed: usize,
+    source_ref: &str,
+    target_branch: &str,
+) -> String {
+    template
+        .replace("{files_changed}", &files_changed.to_string())
+        .replace("{source_ref}", source_ref)
+        .replace("{target_branch}", target_branch)
+}
+
+/// Whether error is caused by a full disk or an exceeded quota
+pub fn is_disk_full_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            return matches!(e.raw_os_error(), Some(libc::ENOSPC) | Some(libc::EDQUOT));
+        }
+        // libgit2 reports OS errors with the message of strerror
+        match e.downcast_ref::<GitError>() {
+            Some(GitError::Unknown(e)) => {
+                e.class() == ErrorClass::Os
+                    && (e.message().contains("No space left on device")
+                        || e.message().contains("quota exceeded"))
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Error of opening repository, telling missing repository apart
+fn open_error(e: git2::Error) -> anyhow::Error {
+    if e.code() == ErrorCode::NotFound {
+        anyhow!(GitError::NoRepository(e))
+    } else {
+        anyhow!(GitError::Unknown(e))
+    }
+}
+
+/// Whether path is the file created by hooks to pause saving
+pub fn is_pause_file(path: &Path) -> bool {
+    path.ends_with(Path::new(".git").join(PAUSE_FILE))
+}
+
+/// Whether path is outside root or reached through a symbolic link to a directory in root
+///
+/// Contents behind symbolic links are not saved, since links may point outside
+/// the repository or form loops. The link itself is saved as a link
+pub fn is_beyond_symlink(root: &Path, path: &Path) -> bool {
+    let Ok(rel) = path.strip_prefix(root) else {
+        return true;
+    };
+    rel.parent()
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .filter(|a| !a.as_os_str().is_empty())
+        .any(|a| {
+            root.join(a)
+                .symlink_metadata()
+                .is_ok_and(|m| m.file_type().is_symlink())
+        })
+}
+
+/// Pathspec of files matched by redaction rule glob
+///
+/// `**/` also matches no directory like in gitignore, so `**/*.env` matches `.env`
+fn redact_pathspec(glob: &str) -> Result<Pathspec> {
+    let mut specs = vec![glob.to_string(), glob.replace("/**/", "/")];
+    if let Some(rest) = glob.strip_prefix("**/") {
+        specs.push(rest.replace("/**/", "/"));
+    }
+    Pathspec::new(specs).with_context(|| format!("Invalid redact glob: {}", glob))
+}
+
+/// Whether hook can run shell commands, i.e. has no shebang or a shell one
+fn is_shell_script(script: &str) -> bool {
+    let Some(shebang) = script.lines().next().and_then(|l| l.strip_prefix("#!")) else {
+        return true;
+    };
+    let mut words = shebang.split_whitespace();
+    let mut interpreter = words.next().unwrap_or_default();
+    if interpreter.ends_with("/env") {
+        interpreter = words.find(|w| !w.starts_with('-')).unwrap_or_default();
+    }
+    let name = interpreter.rsplit('/').next().unwrap_or_default();
+    HOOK_SHELLS.contains(&name)
+}
+
+/// Script called by hooks with their name, which pauses or resumes saving
+fn hook_script() -> String {
+    format!(
+        r#"#!/bin/sh
+# Pause autosave during rebase and merge, called by hooks installed by autosave
+pause="$(git rev-parse --git-dir)/{}"
+case "$1" in
+pre-rebase|pre-merge-commit)
+    touch "$pause"
+    ;;
+post-checkout)
+    # Rebase checks out a detached HEAD when it starts, which must not resume saving
+    if git symbolic-ref -q HEAD >/dev/null &&
+        [ ! -d "$(git rev-parse --git-path rebase-merge)" ] &&
+        [ ! -d "$(git rev-parse --git-path rebase-apply)" ]; then
+        rm -f "$pause"
+    fi
+    ;;
+*)
+    rm -f "$pause"
+    ;;
+esac
+exit 0
+"#,
+        PAUSE_FILE
+    )
+}
+
+/// Write script and make it executable
+fn write_executable(path: &Path, script: &str) -> Result<()> {
+    fs::write(path, script).with_context(|| format!("Failed to write hook: {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to set hook permissions: {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Convert path bytes from libgit2 to path without losing non-UTF-8 names
+fn bytes_to_path(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt as _;
+        PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(bytes).as_ref())
+    }
+}
+
+/// Write file atomically
+///
+/// Contents are written to a temporary file, synced and renamed over the file,
+/// so readers never see a partially written file even after a crash
+fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    // Temporary file name is unique so that concurrent writers do not collide
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = path.with_file_name(tmp_name);
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents.as_ref())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
 /// Git repository object
 pub struct GitRepo(Repository);
 
 impl GitRepo {
+    /// Wait for lock of this repository shared by all processes
+    ///
+    /// Held while the autosave branch is updated, so that watcher saves and commands
+    /// like `autosave save` never run concurrently.
+    /// Lock is released when the returned file is closed, also if the process dies
+    fn repo_lock(&self) -> Result<File> {
+        let path = self.0.path().join(LOCK_FILE);
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file: {}", path.display()))?;
+        file.lock()
+            .with_context(|| format!("Failed to lock {}", path.display()))?;
+        Ok(file)
+    }
+
     /// Create new repository object
-    pub fn new(dir: impl AsRef<str>) -> Result<Self> {
-        let repo = Repository::open(dir.as_ref()).map_err(|e| {
-            let code = e.code();
-            if code == ErrorCode::NotFound {
-                anyhow!(GitError::NoRepository(e))
-            } else {
-                anyhow!(GitError::Unknown(e))
-            }
-        })?;
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let repo = Repository::open(dir.as_ref()).map_err(open_error)?;
         Ok(Self(repo))
     }
 
+    /// Create repository object of the repository containing specified directory
+    pub fn discover(dir: impl AsRef<Path>) -> Result<Self> {
+        let repo = Repository::discover(dir.as_ref()).map_err(open_error)?;
+        Ok(Self(repo))
+    }
+
+    /// Working directory of the repository, `None` if bare
+    pub fn workdir(&self) -> Option<&Path> {
+        self.0.workdir()
+    }
+
     fn head(&self) -> Result<Reference<'_>> {
         self.0
             .head()
@@ -44,7 +319,7 @@ impl GitRepo {
             .context("Failed to get HEAD reference")
     }
 
-    fn get_branch(&self, name: impl AsRef<str>) -> Result<Option<Branch>> {
+    fn get_branch(&self, name: impl AsRef<str>) -> Result<Option<Branch<'_>>> {
         match self.0.find_branch(name.as_ref(), BranchType::Local) {
             Ok(b) => Ok(Some(b)),
             Err(e) => {
@@ -57,7 +332,75 @@ impl GitRepo {
             }
         }
     }
-    fn get_or_create_branch(&self, name: impl AsRef<str>) -> Result<Branch> {
+    /// Call `f` with path and id of every blob in tree
+    ///
+    /// `Tree::walk` fails on non-UTF-8 directory names, so the tree is walked by hand
+    fn walk_blobs(
+        &self,
+        tree: &Tree<'_>,
+        prefix: &Path,
+        f: &mut impl FnMut(PathBuf, Oid),
+    ) -> Result<()> {
+        for entry in tree.iter() {
+            let path = prefix.join(bytes_to_path(entry.name_bytes()));
+            match entry.kind() {
+                Some(ObjectType::Blob) => f(path, entry.id()),
+                Some(ObjectType::Tree) => {
+                    let subtree = self
+                        .0
+                        .find_tree(entry.id())
+                        .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+                    self.walk_blobs(&subtree, &path, f)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Find another worktree of this repository in which specified branch is checked out
+    fn find_checkout_elsewhere(&self, name: impl AsRef<str>) -> Result<Option<PathBuf>> {
+        let ref_name = format!("refs/heads/{}", name.as_ref());
+        let mut repos = Vec::new();
+        if self.0.is_worktree() {
+            // Git directory of a linked worktree points to the main one by `commondir` file
+            let common = fs::read_to_string(self.0.path().join("commondir"))
+                .context("Failed to read commondir")?;
+            let common = self.0.path().join(common.trim());
+            repos.push(Repository::open(common).map_err(|e| anyhow!(GitError::Unknown(e)))?);
+        }
+        let names = self
+            .0
+            .worktrees()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        for name in names.iter().flatten() {
+            // Worktrees whose directory was removed are not opened
+            let Ok(repo) = self
+                .0
+                .find_worktree(name)
+                .and_then(|wt| Repository::open_from_worktree(&wt))
+            else {
+                continue;
+            };
+            repos.push(repo);
+        }
+        let own = fs::canonicalize(self.0.path()).ok();
+        for repo in repos {
+            if fs::canonicalize(repo.path()).ok() == own {
+                continue;
+            }
+            let checked_out = repo
+                .find_reference("HEAD")
+                .ok()
+                .is_some_and(|head| head.symbolic_target() == Some(ref_name.as_str()));
+            if checked_out {
+                return Ok(Some(repo.workdir().unwrap_or(repo.path()).to_path_buf()));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_or_create_branch(&self, name: impl AsRef<str>) -> Result<Branch<'_>> {
         match self.get_branch(&name)? {
             Some(b) => Ok(b),
             None => {
@@ -111,19 +454,89 @@ impl GitRepo {
         self.change_head_ref(&ref_name, message)
     }
 
+    /// Get repository index reloaded from disk if it was changed
     fn get_current_index(&self) -> Result<Index> {
-        self.0.index().map_err(|e| anyhow!(GitError::Unknown(e)))
+        let mut index = self.0.index().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        index
+            .read(false)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        Ok(index)
     }
 
-    fn add_cwd_all(&self) -> Result<()> {
-        let mut index = self.0.index().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+    /// Add whole working directory to index, reporting progress of `total` changed files
+    fn add_cwd_all(&self, total: usize) -> Result<()> {
+        let mut index = self.get_current_index()?;
+        let mut done = 0;
+        let mut reported = Instant::now();
+        let mut report = |_: &Path, _: &[u8]| {
+            done += 1;
+            if PROGRESS_INTERVAL <= reported.elapsed() {
+                self.report_progress(SavePhase::Hashing, done, total);
+                reported = Instant::now();
+            }
+            0
+        };
         index
-            .add_all(["."], IndexAddOption::DEFAULT, None)
+            .add_all(["."], IndexAddOption::DEFAULT, Some(&mut report))
             .map_err(|e| anyhow!(GitError::Unknown(e)))?;
         index.write().map_err(|e| anyhow!(GitError::Unknown(e)))?;
         Ok(())
     }
 
+    /// Add changed paths to index
+    ///
+    /// Paths are absolute or relative to working directory.
+    /// Directories are added recursively and missing paths are removed from index
+    fn add_paths<'p>(&self, paths: impl IntoIterator<Item = &'p PathBuf>) -> Result<()> {
+        let workdir = self
+            .0
+            .workdir()
+            .ok_or_else(|| anyhow!("Repository has no working directory"))?
+            .to_path_buf();
+        let mut index = self.get_current_index()?;
+        for path in paths {
+            let rel = path.strip_prefix(&workdir).unwrap_or(path);
+            if rel.as_os_str().is_empty() || rel.is_absolute() || rel.starts_with(".git") {
+                continue;
+            }
+            if is_beyond_symlink(&workdir, &workdir.join(rel)) {
+                continue;
+            }
+            match workdir.join(rel).symlink_metadata() {
+                Ok(m) if m.is_dir() => index
+                    .add_all(
+                        [rel],
+                        IndexAddOption::DEFAULT | IndexAddOption::DISABLE_PATHSPEC_MATCH,
+                        None,
+                    )
+                    .map_err(|e| anyhow!(GitError::Unknown(e)))?,
+                Ok(_) => {
+                    let tracked = index.get_path(rel, 0).is_some();
+                    if tracked
+                        || !self
+                            .0
+                            .is_path_ignored(rel)
+                            .map_err(|e| anyhow!(GitError::Unknown(e)))?
+                    {
+                        index
+                            .add_path(rel)
+                            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+                    }
+                }
+                Err(_) => {
+                    index
+                        .remove_path(rel)
+                        .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+                    index
+                        .remove_dir(rel, 0)
+                        .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+                }
+            }
+        }
+        index.write().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        Ok(())
+    }
+
     fn get_current_head_name(&self) -> Result<ReferenceName> {
         let head = self.head()?;
         if self
@@ -141,35 +554,154 @@ impl GitRepo {
         }
     }
 
-    fn get_ref_workdir_diff(&self, reference: &Reference<'_>) -> Result<Diff<'_>> {
+    /// Get diff between reference and working directory limited to specified paths
+    fn get_ref_workdir_diff(
+        &self,
+        reference: &Reference<'_>,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+        options: &SaveOptions,
+    ) -> Result<Diff<'_>> {
         let tree = reference
             .peel_to_tree()
             .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let mut diff_options = DiffOptions::new();
+        diff_options
+            .include_untracked(true)
+            .recurse_untracked_dirs(!options.large_repo)
+            .disable_pathspec_match(true);
+        for p in paths {
+            diff_options.pathspec(p.as_ref());
+        }
         self.0
-            .diff_tree_to_workdir(
-                Some(&tree),
-                Some(
-                    DiffOptions::new()
-                        .include_untracked(true)
-                        .recurse_untracked_dirs(true),
-                ),
-            )
+            .diff_tree_to_workdir(Some(&tree), Some(&mut diff_options))
             .map_err(|e| anyhow!(GitError::Unknown(e)))
     }
-    fn is_saved(&self, branch: impl AsRef<str>) -> Result<bool> {
-        let head = self.head()?;
-        let diff = self.get_ref_workdir_diff(&head)?;
-        let stats = diff.stats().map_err(|e| anyhow!(GitError::Unknown(e)))?;
-        if stats.files_changed() == 0 {
+    /// Get paths changed from HEAD in index or working directory
+    ///
+    /// Index is used as stat cache so unchanged files are not hashed
+    ///
+    /// In large repository mode untracked directories are reported as a single path
+    fn get_changed_paths(&self, options: &SaveOptions) -> Result<HashSet<PathBuf>> {
+        let statuses = self
+            .0
+            .statuses(Some(
+                StatusOptions::new()
+                    .include_untracked(true)
+                    .recurse_untracked_dirs(!options.large_repo)
+                    .update_index(options.large_repo)
+                    .exclude_submodules(true),
+            ))
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        Ok(statuses
+            .iter()
+            .map(|s| bytes_to_path(s.path_bytes()))
+            .collect())
+    }
+    /// Cheaply check whether changed paths plausibly keep the saved state
+    ///
+    /// Paths are absolute or relative to working directory.
+    /// Returns `true` only if every path is in `.git`, an ignored untracked file,
+    /// or a file whose stat matches the index entry which is same as HEAD and branch.
+    /// Entries modified in the same second as the index are racy and treated as changed
+    pub fn is_unchanged<'p>(
+        &self,
+        branch: impl AsRef<str>,
+        paths: impl IntoIterator<Item = &'p PathBuf>,
+    ) -> Result<bool> {
+        let workdir = self
+            .0
+            .workdir()
+            .ok_or_else(|| anyhow!("Repository has no working directory"))?
+            .to_path_buf();
+        let index = self.get_current_index()?;
+        let index_mtime = fs::metadata(self.0.path().join("index"))
+            .and_then(|m| m.modified())
+            .ok();
+        let mut trees = None;
+        for path in paths {
+            let rel = path.strip_prefix(&workdir).unwrap_or(path);
+            if rel.starts_with(".git") {
+                continue;
+            }
+            if rel.as_os_str().is_empty() || rel.is_absolute() {
+                return Ok(false);
+            }
+            let Ok(meta) = workdir.join(rel).symlink_metadata() else {
+                return Ok(false);
+            };
+            if meta.is_dir() {
+                return Ok(false);
+            }
+            let Some(entry) = index.get_path(rel, 0) else {
+                if self
+                    .0
+                    .is_path_ignored(rel)
+                    .map_err(|e| anyhow!(GitError::Unknown(e)))?
+                {
+                    continue;
+                }
+                return Ok(false);
+            };
+            let Ok(mtime) = meta.modified() else {
+                return Ok(false);
+            };
+            let mtime = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+            let racy = index_mtime.is_none_or(|t| {
+                t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() <= mtime.as_secs()
+            });
+            if racy
+                || entry.file_size != meta.len() as u32
+                || entry.mtime.seconds() as u64 != mtime.as_secs()
+                || entry.mtime.nanoseconds() != mtime.subsec_nanos()
+            {
+                return Ok(false);
+            }
+            if trees.is_none() {
+                let Some(branch) = self.get_branch(&branch)? else {
+                    return Ok(false);
+                };
+                let head_tree = self
+                    .head()?
+                    .peel_to_tree()
+                    .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+                let branch_tree = branch
+                    .get()
+                    .peel_to_tree()
+                    .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+                trees = Some([head_tree, branch_tree]);
+            }
+            for tree in trees.iter().flatten() {
+                if tree.get_path(rel).ok().map(|e| e.id()) != Some(entry.id) {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+    fn is_saved(
+        &self,
+        branch: impl AsRef<str>,
+        changed: &HashSet<PathBuf>,
+        options: &SaveOptions,
+    ) -> Result<bool> {
+        if changed.is_empty() {
             return Ok(true);
         }
-        if let Some(branch) = self.get_branch(branch)? {
-            let diff = self.get_ref_workdir_diff(branch.get())?;
-            let stats = diff.stats().map_err(|e| anyhow!(GitError::Unknown(e)))?;
-            Ok(stats.files_changed() == 0)
-        } else {
-            Ok(false)
+        let Some(branch) = self.get_branch(branch)? else {
+            return Ok(false);
+        };
+        // Files not changed from HEAD must be same in HEAD and branch
+        let head = self.head()?;
+        let diff = self.get_ref_ref_diff(&head, branch.get())?;
+        for delta in diff.deltas() {
+            let paths = [delta.old_file().path(), delta.new_file().path()];
+            if paths.into_iter().flatten().any(|p| !changed.contains(p)) {
+                return Ok(false);
+            }
         }
+        // Changed files must be same in working directory and branch
+        let diff = self.get_ref_workdir_diff(branch.get(), changed, options)?;
+        Ok(diff.deltas().len() == 0)
     }
 
     fn get_ref_ref_diff(&self, old: &Reference<'_>, new: &Reference<'_>) -> Result<Diff<'_>> {
@@ -224,7 +756,19 @@ impl GitRepo {
         let tt = their
             .peel_to_tree()
             .map_err(|e| anyhow!(GitError::Unknown(e)))?;
-        let mut before_index = self.0.index().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let files_changed = self
+            .0
+            .diff_tree_to_tree(Some(&ancestor), Some(&ot), None)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?
+            .deltas()
+            .len();
+        let message = render_merge_message(
+            message.as_ref(),
+            files_changed,
+            our.shorthand().unwrap_or_default(),
+            their.shorthand().unwrap_or_default(),
+        );
+        let mut before_index = self.get_current_index()?;
         let mut index = self
             .0
             .merge_trees(&ancestor, &ot, &tt, None)
@@ -232,14 +776,17 @@ impl GitRepo {
         self.0
             .set_index(&mut index)
             .map_err(|e| anyhow!(GitError::Unknown(e)))?;
-        let commit = self.commit(&[&tc, &oc], message)?;
-        self.0
-            .cleanup_state()
-            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let commit = self.commit(&[&tc, &oc], message).and_then(|commit| {
+            self.0
+                .cleanup_state()
+                .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+            Ok(commit)
+        });
+        // Index is put back even if the commit failed
         self.0
             .set_index(&mut before_index)
             .map_err(|e| anyhow!(GitError::Unknown(e)))?;
-        Ok(Some(commit))
+        Ok(Some(commit?))
     }
     /// Merge ref to HEAD if there are no diffs
     fn auto_merge(&self, from: &ReferenceName, message: impl AsRef<str>) -> Result<Option<Oid>> {
@@ -261,12 +808,15 @@ impl GitRepo {
 
     /// Backup current index to entries
     fn backup_index(&self) -> Result<Vec<IndexEntry>> {
-        let index = self.0.index().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let index = self.get_current_index()?;
         Ok(index.iter().collect())
     }
     /// Restore index from entries
+    ///
+    /// Index is replaced as a whole, so entries removed from it stay removed
     fn restore_index(&self, entries: impl IntoIterator<Item = IndexEntry>) -> Result<()> {
         let mut index = self.get_current_index()?;
+        index.clear().map_err(|e| anyhow!(GitError::Unknown(e)))?;
         for entry in entries.into_iter() {
             index
                 .add(&entry)
@@ -298,27 +848,279 @@ impl GitRepo {
     }
 
     /// Create new commit on current HEAD
-    fn commit_on_current_head(&self, message: impl AsRef<str>) -> Result<Oid> {
+    ///
+    /// Nothing is committed if the index has same tree as HEAD,
+    /// which happens when changes are redacted away
+    ///
+    /// If configured, changed files are listed in the commit message body,
+    /// and the latest autosave commit is amended when too many commits were made recently
+    fn commit_on_current_head(
+        &self,
+        message: impl AsRef<str>,
+        options: &SaveOptions,
+    ) -> Result<Option<Oid>> {
         let commit = self
             .head()?
             .peel_to_commit()
             .map_err(|e| anyhow!(GitError::Unknown(e)))?;
-        self.commit(&[&commit], &message)
+        let tree = self
+            .0
+            .index()
+            .and_then(|mut index| index.write_tree())
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        if tree == commit.tree_id() {
+            return Ok(None);
+        }
+        let squash = match options.max_commits_per_hour {
+            Some(max) => max as usize <= self.count_recent_saves(&commit, message.as_ref())?,
+            None => false,
+        };
+        // Amended commit replaces the latest one, so changes are summarized from its parent
+        let base = if squash {
+            commit
+                .parent(0)
+                .map_err(|e| anyhow!(GitError::Unknown(e)))?
+        } else {
+            commit.clone()
+        };
+        let message = if options.commit_summary {
+            let body = self.summarize_change(base.tree_id(), tree)?;
+            format!("{}\n\n{}", message.as_ref(), body)
+        } else {
+            message.as_ref().to_string()
+        };
+        if !squash {
+            return self.commit(&[&commit], &message).map(Some);
+        }
+        let tree = self
+            .0
+            .find_tree(tree)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let sig = self
+            .0
+            .signature()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let oid = self
+            .0
+            .commit(None, &sig, &sig, &message, &tree, &[&base])
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        self.head()?
+            .set_target(oid, "autosave: amend")
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        Ok(Some(oid))
+    }
+
+    /// Count autosave commits made in the last hour, following first parents from `commit`
+    ///
+    /// Counting stops at a merge or a commit not made by autosave,
+    /// so such commits are never amended
+    fn count_recent_saves(&self, commit: &Commit<'_>, message: &str) -> Result<usize> {
+        let since = SystemTime::now() - Duration::from_secs(60 * 60);
+        let mut count = 0;
+        let mut commit = commit.clone();
+        while commit.parent_count() == 1
+            && since <= from_git_time(commit.time())
+            && commit.message().is_some_and(|m| m.starts_with(message))
+        {
+            count += 1;
+            commit = commit
+                .parent(0)
+                .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        }
+        Ok(count)
+    }
+
+    /// List files changed between trees in `git status --short` style, truncated
+    fn summarize_change(&self, old: Oid, new: Oid) -> Result<String> {
+        let old = self
+            .0
+            .find_tree(old)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let new = self
+            .0
+            .find_tree(new)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let diff = self
+            .0
+            .diff_tree_to_tree(Some(&old), Some(&new), None)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let mut lines: Vec<String> = diff
+            .deltas()
+            .take(SUMMARY_MAX_FILES)
+            .map(|delta| {
+                let (mark, file) = match delta.status() {
+                    Delta::Added => ("A", delta.new_file()),
+                    Delta::Deleted => ("D", delta.old_file()),
+                    _ => ("M", delta.new_file()),
+                };
+                let path = file.path_bytes().map(bytes_to_path).unwrap_or_default();
+                format!("{} {}", mark, path.display())
+            })
+            .collect();
+        let rest = diff.deltas().len().saturating_sub(SUMMARY_MAX_FILES);
+        if 0 < rest {
+            lines.push(format!("... and {} more", rest));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Rewrite contents of index entries matching redaction rules
+    ///
+    /// Only blobs are rewritten, working directory files are not touched
+    fn redact_index(&self, rules: &[RedactRule]) -> Result<()> {
+        if rules.is_empty() {
+            return Ok(());
+        }
+        let mut index = self.get_current_index()?;
+        let entries: Vec<IndexEntry> = index.iter().collect();
+        for rule in rules {
+            let spec = redact_pathspec(&rule.glob)?;
+            for entry in &entries {
+                let path = bytes_to_path(&entry.path);
+                if !spec.matches_path(&path, PathspecFlags::DEFAULT) {
+                    continue;
+                }
+                // Entry may be rewritten by previous rule
+                let Some(mut entry) = index.get_path(&path, 0) else {
+                    continue;
+                };
+                let blob = self
+                    .0
+                    .find_blob(entry.id)
+                    .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+                let content = blob.content();
+                let redacted = rule.pattern.replace_all(content, rule.replace.as_bytes());
+                if redacted == content {
+                    continue;
+                }
+                entry.id = self
+                    .0
+                    .blob(&redacted)
+                    .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+                entry.file_size = redacted.len() as u32;
+                index
+                    .add(&entry)
+                    .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+            }
+        }
+        index.write().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        Ok(())
+    }
+
+    /// List files which saving whole working directory to specified branch would commit,
+    /// and ignored files which would be excluded
+    ///
+    /// Nothing is written to the repository
+    pub fn plan_save(
+        &self,
+        branch: impl AsRef<str>,
+        options: &SaveOptions,
+    ) -> Result<Vec<PlannedFile>> {
+        let base = match self.get_branch(&branch)? {
+            Some(b) => Some(b.into_reference()),
+            None => self.0.head().ok(),
+        };
+        let tree = base
+            .map(|r| r.peel_to_tree())
+            .transpose()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let diff = self
+            .0
+            .diff_tree_to_workdir(
+                tree.as_ref(),
+                Some(
+                    DiffOptions::new()
+                        .include_untracked(true)
+                        .recurse_untracked_dirs(!options.large_repo)
+                        .include_ignored(true)
+                        .include_typechange(true),
+                ),
+            )
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let specs = options
+            .redact
+            .iter()
+            .map(|r| redact_pathspec(&r.glob))
+            .collect::<Result<Vec<_>>>()?;
+        let mut planned = Vec::new();
+        for delta in diff.deltas() {
+            let file = match delta.status() {
+                Delta::Deleted => delta.old_file(),
+                _ => delta.new_file(),
+            };
+            let Some(path) = file.path_bytes().map(bytes_to_path) else {
+                continue;
+            };
+            let status = match delta.status() {
+                Delta::Untracked => Delta::Added,
+                s => s,
+            };
+            let redacted = status != Delta::Deleted
+                && status != Delta::Ignored
+                && specs
+                    .iter()
+                    .any(|s| s.matches_path(&path, PathspecFlags::DEFAULT));
+            planned.push(PlannedFile {
+                path,
+                status,
+                redacted,
+            });
+        }
+        Ok(planned)
     }
 
     /// Save current working directory to specified branch
+    ///
+    /// If `changed` is specified, only these paths are updated in the saved tree.
+    /// Otherwise whole working directory is added.
+    /// Fails with `GitError::InProgress` without saving while a merge or rebase is in progress
     pub fn save(
         &self,
         branch_name: impl AsRef<str>,
         commit_message: impl AsRef<str>,
         merge_message: impl AsRef<str>,
+        changed: Option<&HashSet<PathBuf>>,
+        options: &SaveOptions,
     ) -> Result<()> {
+        let _lock = self.repo_lock()?;
         let state = self.0.state();
         if state != RepositoryState::Clean {
-            return Ok(());
+            return Err(anyhow!(GitError::InProgress(state)));
+        }
+        let lock = self.0.path().join("index.lock");
+        if lock.exists() {
+            return Err(anyhow!(GitError::Locked(lock)));
         }
+        // Committing to a branch checked out elsewhere would change that worktree under the user
+        if let Some(path) = self.find_checkout_elsewhere(&branch_name)? {
+            return Err(anyhow!(GitError::CheckedOut(
+                branch_name.as_ref().to_string(),
+                path
+            )));
+        }
+
+        if changed.is_none() {
+            self.report_progress(SavePhase::Scanning, 0, 0);
+        }
+        let result =
+            self.save_changes(branch_name, commit_message, merge_message, changed, options);
+        if changed.is_none() {
+            // Progress file may be missing if reporting failed
+            let _ = fs::remove_file(self.0.path().join(PROGRESS_FILE));
+        }
+        result
+    }
 
-        if self.is_saved(&branch_name)? {
+    fn save_changes(
+        &self,
+        branch_name: impl AsRef<str>,
+        commit_message: impl AsRef<str>,
+        merge_message: impl AsRef<str>,
+        changed: Option<&HashSet<PathBuf>>,
+        options: &SaveOptions,
+    ) -> Result<()> {
+        let changed_paths = self.get_changed_paths(options)?;
+        if self.is_saved(&branch_name, &changed_paths, options)? {
             return Ok(());
         }
 
@@ -329,48 +1131,1220 @@ impl GitRepo {
             .backup_index()
             .context("Failed to get index entries backup")?;
 
+        let saved = self
+            .stage_on_branch(
+                &branch_name,
+                &current_head,
+                &merge_message,
+                changed,
+                changed_paths.len(),
+            )
+            .and_then(|()| {
+                self.redact_index(&options.redact)
+                    .context("Failed to redact snapshot")
+            })
+            .and_then(|()| {
+                self.commit_on_current_head(&commit_message, options)
+                    .map(|_| ())
+            });
+
+        // HEAD and index are restored even if saving failed midway
+        let restored = self
+            .change_head_ref(&current_head, "")
+            .context("Failed to restore HEAD reference")
+            .and_then(|_| {
+                self.restore_index(current_index_entries)
+                    .context("Failed to restore index entries")
+            });
+        match (saved, restored) {
+            (Err(e), Err(r)) => Err(r.context(format!("Failed to recover from error: {:#}", e))),
+            (saved, restored) => saved.and(restored),
+        }
+    }
+
+    /// Switch HEAD to specified branch and add working directory to index
+    ///
+    /// `total` is the number of changed files reported as progress of whole directory add.
+    /// HEAD is left on the branch, caller restores it
+    fn stage_on_branch(
+        &self,
+        branch_name: impl AsRef<str>,
+        current_head: &ReferenceName,
+        merge_message: impl AsRef<str>,
+        changed: Option<&HashSet<PathBuf>>,
+        total: usize,
+    ) -> Result<()> {
         self.change_head_branch(&branch_name, "")
             .context("Failed to change branch")?;
+        let merged = self.auto_merge(current_head, &merge_message)?;
+        // Merge commit brings changes outside of changed paths, so add everything
+        match changed {
+            Some(paths) if merged.is_none() => self.add_paths(paths)?,
+            _ => {
+                self.add_cwd_all(total)?;
+                self.report_progress(SavePhase::Committing, total, total);
+            }
+        }
+        Ok(())
+    }
 
-        // Run auto merge
-        if let Err(e) = self.auto_merge(&current_head, &merge_message) {
-            // Restore current HEAD if error occurred
-            self.change_head_ref(&current_head, "").with_context(|| {
-                format!(
-                    "Failed to restore HEAD reference from recovering error: {}",
-                    &e
-                )
-            })?;
-            return Err(e);
+    /// Walk snapshots on specified branch from newest to oldest
+    ///
+    /// Only first parents are followed, so merged commits of other branches are skipped.
+    /// Walk stops at the fork point, since commits reachable from other local branches
+    /// or a detached HEAD were not created by autosave
+    fn walk_branch(
+        &self,
+        branch: impl AsRef<str>,
+    ) -> Result<impl Iterator<Item = Result<Commit<'_>>> + '_> {
+        let branch = self
+            .get_branch(&branch)?
+            .ok_or_else(|| anyhow!("Branch not found: {}", branch.as_ref()))?;
+        let oid = branch
+            .get()
+            .peel_to_commit()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?
+            .id();
+        let mut walk = self
+            .0
+            .revwalk()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        walk.simplify_first_parent()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        walk.push(oid).map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let name = branch.get().name().map(str::to_string);
+        let branches = self
+            .0
+            .branches(Some(BranchType::Local))
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let mut others = Vec::new();
+        for b in branches {
+            let (b, _) = b.map_err(|e| anyhow!(GitError::Unknown(e)))?;
+            if b.get().name().map(str::to_string) != name {
+                others.extend(b.get().target());
+            }
+        }
+        // HEAD is on the branch itself while saving
+        if let Ok(head) = self.0.head() {
+            if !head.is_branch() {
+                others.extend(head.target());
+            }
+        }
+        for other in others {
+            walk.hide(other)
+                .map_err(|e| anyhow!(GitError::Unknown(e)))?;
         }
+        Ok(walk.map(|oid| {
+            let oid = oid.map_err(|e| anyhow!(GitError::Unknown(e)))?;
+            self.0
+                .find_commit(oid)
+                .map_err(|e| anyhow!(GitError::Unknown(e)))
+        }))
+    }
 
-        if let Err(e) = self.add_cwd_all() {
-            // Restore current HEAD if error occurred
-            self.change_head_ref(&current_head, "").with_context(|| {
-                format!(
-                    "Failed to restore HEAD reference from recovering error: {}",
-                    &e
-                )
+    /// Find the latest snapshot on specified branch created at or before specified time
+    pub fn find_snapshot_at(
+        &self,
+        branch: impl AsRef<str>,
+        at: SystemTime,
+    ) -> Result<Option<Commit<'_>>> {
+        for commit in self.walk_branch(branch)? {
+            let commit = commit?;
+            if from_git_time(commit.time()) <= at {
+                return Ok(Some(commit));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Restore tree of specified commit
+    ///
+    /// Files are written to the working directory, or `target` if specified.
+    /// If `paths` is not empty, only files under these paths are restored.
+    /// Tracked files which are not in the tree are deleted. Nothing is overwritten or
+    /// deleted unless `force`.
+    /// Returns existing files which are (or would be, if `dry_run`) overwritten, as
+    /// `Delta::Modified`, or deleted, as `Delta::Deleted`
+    pub fn restore_tree(
+        &self,
+        commit: &Commit<'_>,
+        paths: &[PathBuf],
+        target: Option<&Path>,
+        dry_run: bool,
+        force: bool,
+    ) -> Result<Vec<(PathBuf, Delta)>> {
+        let tree = commit.tree().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let base = match target {
+            Some(t) => t.to_path_buf(),
+            None => self
+                .0
+                .workdir()
+                .ok_or_else(|| anyhow!("Repository has no working directory"))?
+                .to_path_buf(),
+        };
+
+        let mut changes = Vec::new();
+        self.walk_blobs(&tree, Path::new(""), &mut |path, id| {
+            if !paths.is_empty() && !paths.iter().any(|p| path.starts_with(p)) {
+                return;
+            }
+            let file = base.join(&path);
+            if file.symlink_metadata().is_ok()
+                && Oid::hash_file(ObjectType::Blob, &file).ok() != Some(id)
+            {
+                changes.push((path, Delta::Modified));
+            }
+        })?;
+        // Checkout compares against HEAD, so it removes files of HEAD which are not in tree
+        if let Some(head) = self.0.head().ok().and_then(|h| h.peel_to_tree().ok()) {
+            let mut restored = HashSet::new();
+            self.walk_blobs(&tree, Path::new(""), &mut |path, _| {
+                restored.insert(path);
             })?;
-            return Err(e);
-        }
-        if let Err(e) = self.commit_on_current_head(&commit_message) {
-            // Restore current HEAD if error occurred
-            self.change_head_ref(&current_head, "").with_context(|| {
-                format!(
-                    "Failed to restore HEAD reference from recovering error: {}",
-                    &e
-                )
+            self.walk_blobs(&head, Path::new(""), &mut |path, _| {
+                if (paths.is_empty() || paths.iter().any(|p| path.starts_with(p)))
+                    && !restored.contains(&path)
+                    && base.join(&path).symlink_metadata().is_ok()
+                {
+                    changes.push((path, Delta::Deleted));
+                }
             })?;
-            return Err(e);
         }
+        if dry_run {
+            return Ok(changes);
+        }
+        if !changes.is_empty() && !force {
+            let list: Vec<_> = changes
+                .iter()
+                .map(|(p, status)| match status {
+                    Delta::Deleted => format!("  delete: {}", p.display()),
+                    _ => format!("  overwrite: {}", p.display()),
+                })
+                .collect();
+            return Err(anyhow!(
+                "Refusing to overwrite or delete files (use --force to do so):\n{}",
+                list.join("\n")
+            ));
+        }
+
+        if let Some(t) = target {
+            fs::create_dir_all(t)
+                .with_context(|| format!("Failed to create directory: {}", t.display()))?;
+        }
+        let mut checkout = Self::restore_checkout(paths, target);
+        self.0
+            .checkout_tree(tree.as_object(), Some(&mut checkout))
+            .map_err(|e| anyhow!(GitError::Unknown(e)))
+            .context("Failed to checkout tree")?;
+        Ok(changes)
+    }
+
+    /// Checkout options writing tree to working directory or `target`
+    fn restore_checkout<'cb>(paths: &[PathBuf], target: Option<&Path>) -> CheckoutBuilder<'cb> {
+        let mut checkout = CheckoutBuilder::new();
+        checkout.force().recreate_missing(true).update_index(false);
+        if let Some(t) = target {
+            checkout.target_dir(t);
+        }
+        for p in paths {
+            checkout.path(p);
+        }
+        checkout
+    }
+
+    /// Get diff between first parent of commit and commit
+    fn get_parent_commit_diff(
+        &self,
+        commit: &Commit<'_>,
+        options: &mut DiffOptions,
+    ) -> Result<Diff<'_>> {
+        let tree = commit.tree().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let parent_tree = match commit.parent(0) {
+            Ok(p) => Some(p.tree().map_err(|e| anyhow!(GitError::Unknown(e)))?),
+            Err(_) => None,
+        };
+        self.0
+            .diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), Some(options))
+            .map_err(|e| anyhow!(GitError::Unknown(e)))
+    }
+
+    /// List snapshots on specified branch in which specified file changed
+    ///
+    /// If `path` is `None`, snapshots changing any file are listed
+    pub fn file_history(
+        &self,
+        branch: impl AsRef<str>,
+        path: Option<&Path>,
+    ) -> Result<Vec<FileChange>> {
+        let mut history = Vec::new();
+        for commit in self.walk_branch(branch)? {
+            let commit = commit?;
+            let mut options = DiffOptions::new();
+            if let Some(p) = path {
+                options.pathspec(p);
+            }
+            let diff = self.get_parent_commit_diff(&commit, &mut options)?;
+            if diff.deltas().len() == 0 {
+                continue;
+            }
+            let stats = diff.stats().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+            let mut patch = String::new();
+            diff.print(DiffFormat::Patch, |_, _, line| {
+                if matches!(line.origin(), '+' | '-' | ' ') {
+                    patch.push(line.origin());
+                }
+                patch.push_str(&String::from_utf8_lossy(line.content()));
+                true
+            })
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+            history.push(FileChange {
+                id: commit.id(),
+                time: commit.time(),
+                message: commit.summary().unwrap_or_default().to_string(),
+                insertions: stats.insertions(),
+                deletions: stats.deletions(),
+                patch,
+            });
+        }
+        Ok(history)
+    }
+
+    /// Export whole history of specified branch as git bundle
+    pub fn export_bundle(&self, branch: impl AsRef<str>, path: impl AsRef<Path>) -> Result<Oid> {
+        let file = File::create(path.as_ref())
+            .with_context(|| format!("Failed to create file: {}", path.as_ref().display()))?;
+        let mut writer = BufWriter::new(file);
+        let oid = self.export_bundle_to(branch, &mut writer)?;
+        writer.flush().context("Failed to write bundle")?;
+        Ok(oid)
+    }
+
+    /// Write whole history of specified branch as git bundle to writer
+    pub fn export_bundle_to(&self, branch: impl AsRef<str>, mut writer: impl Write) -> Result<Oid> {
+        let branch = self
+            .get_branch(&branch)?
+            .ok_or_else(|| anyhow!("Branch not found: {}", branch.as_ref()))?;
+        let reference = branch.into_reference();
+        let oid = reference
+            .peel_to_commit()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?
+            .id();
+        let mut walk = self
+            .0
+            .revwalk()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        walk.push(oid).map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let mut builder = self
+            .0
+            .packbuilder()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        builder
+            .insert_walk(&mut walk)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+
+        write!(
+            writer,
+            "# v2 git bundle\n{} {}\n\n",
+            oid,
+            reference.name().unwrap_or_default()
+        )
+        .context("Failed to write bundle header")?;
+        let mut write_result = Ok(());
+        builder
+            .foreach(|buf| {
+                write_result = writer.write_all(buf);
+                write_result.is_ok()
+            })
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        write_result.context("Failed to write bundle")?;
+        Ok(oid)
+    }
+
+    /// Import git bundle to specified branch
+    ///
+    /// The first reference in the bundle is imported.
+    /// Existing branch is only updated by fast-forward unless `force` is specified
+    pub fn import_bundle(
+        &self,
+        path: impl AsRef<Path>,
+        branch: impl AsRef<str>,
+        force: bool,
+    ) -> Result<Oid> {
+        let file = File::open(path.as_ref())
+            .with_context(|| format!("Failed to open file: {}", path.as_ref().display()))?;
+        self.import_bundle_from(BufReader::new(file), branch, force)
+            .with_context(|| format!("Failed to import bundle: {}", path.as_ref().display()))
+    }
+
+    /// Import git bundle read from reader to specified branch
+    pub fn import_bundle_from(
+        &self,
+        mut reader: impl BufRead,
+        branch: impl AsRef<str>,
+        force: bool,
+    ) -> Result<Oid> {
+        let _lock = self.repo_lock()?;
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .context("Failed to read bundle header")?;
+        if !matches!(line.trim_end(), "# v2 git bundle" | "# v3 git bundle") {
+            return Err(anyhow!("Not a git bundle"));
+        }
+        let mut tip = None;
+        loop {
+            line.clear();
+            reader
+                .read_line(&mut line)
+                .context("Failed to read bundle header")?;
+            let l = line.trim_end();
+            if l.is_empty() {
+                break;
+            }
+            if l.starts_with('@') {
+                // v3 capability
+                continue;
+            }
+            if let Some(prerequisite) = l.strip_prefix('-') {
+                let hex = prerequisite.split(' ').next().unwrap_or_default();
+                let oid = Oid::from_str(hex).map_err(|e| anyhow!(GitError::Unknown(e)))?;
+                if self.0.find_commit(oid).is_err() {
+                    return Err(anyhow!("Bundle requires missing commit: {}", oid));
+                }
+            } else if tip.is_none() {
+                let hex = l.split(' ').next().unwrap_or_default();
+                tip = Some(Oid::from_str(hex).map_err(|e| anyhow!(GitError::Unknown(e)))?);
+            }
+        }
+        let tip = tip.ok_or_else(|| anyhow!("Bundle contains no references"))?;
+
+        let odb = self.0.odb().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let mut writer = odb
+            .packwriter()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        io::copy(&mut reader, &mut writer).context("Failed to write pack")?;
+        writer
+            .commit()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))
+            .context("Failed to write pack")?;
+
+        let commit = self
+            .0
+            .find_commit(tip)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        if let Some(b) = self.get_branch(&branch)? {
+            let current = b
+                .get()
+                .peel_to_commit()
+                .map_err(|e| anyhow!(GitError::Unknown(e)))?
+                .id();
+            let fast_forward = current == tip
+                || self
+                    .0
+                    .graph_descendant_of(tip, current)
+                    .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+            if !fast_forward && !force {
+                return Err(anyhow!(
+                    "Branch {} has diverged from bundle, use another branch or force",
+                    branch.as_ref()
+                ));
+            }
+        }
+        self.0
+            .branch(branch.as_ref(), &commit, true)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))
+            .context("Failed to update branch")?;
+        Ok(tip)
+    }
+
+    /// Search lines matching pattern added or removed by snapshots on specified branch
+    ///
+    /// Snapshots older than `since` are not searched
+    pub fn grep_history(
+        &self,
+        branch: impl AsRef<str>,
+        pattern: &Regex,
+        since: Option<SystemTime>,
+    ) -> Result<Vec<LineMatch>> {
+        let mut matches = Vec::new();
+        for commit in self.walk_branch(branch)? {
+            let commit = commit?;
+            if since.is_some_and(|s| from_git_time(commit.time()) < s) {
+                break;
+            }
+            let diff = self.get_parent_commit_diff(&commit, &mut DiffOptions::new())?;
+            diff.foreach(
+                &mut |_, _| true,
+                None,
+                None,
+                Some(&mut |delta, _, line| {
+                    let added = match line.origin() {
+                        '+' => true,
+                        '-' => false,
+                        _ => return true,
+                    };
+                    let content = String::from_utf8_lossy(line.content());
+                    if pattern.is_match(&content) {
+                        let file = if added {
+                            delta.new_file()
+                        } else {
+                            delta.old_file()
+                        };
+                        matches.push(LineMatch {
+                            id: commit.id(),
+                            time: commit.time(),
+                            path: file.path().map(Path::to_path_buf).unwrap_or_default(),
+                            added,
+                            line: content.trim_end_matches(['\r', '\n']).to_string(),
+                        });
+                    }
+                    true
+                }),
+            )
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        }
+        Ok(matches)
+    }
+
+    /// Drop the tip commit of specified branch
+    ///
+    /// Fails if the commit is reachable from any other reference.
+    /// Returns dropped commit id
+    pub fn undo(&self, branch: impl AsRef<str>) -> Result<Oid> {
+        let _lock = self.repo_lock()?;
+        let branch = self
+            .get_branch(&branch)?
+            .ok_or_else(|| anyhow!("Branch not found: {}", branch.as_ref()))?;
+        if branch.is_head() {
+            return Err(anyhow!("Branch is checked out"));
+        }
+        let mut reference = branch.into_reference();
+        let name = reference.name().unwrap_or_default().to_string();
+        let tip = reference
+            .peel_to_commit()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let parent = tip
+            .parent_id(0)
+            .map_err(|_| anyhow!("Commit has no parent: {}", tip.id()))?;
+
+        let references = self
+            .0
+            .references()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        for r in references {
+            let r = r.map_err(|e| anyhow!(GitError::Unknown(e)))?;
+            let r_name = r.name().unwrap_or_default();
+            if r_name == name {
+                continue;
+            }
+            let Ok(c) = r.peel_to_commit() else {
+                continue;
+            };
+            if c.id() == tip.id()
+                || self
+                    .0
+                    .graph_descendant_of(c.id(), tip.id())
+                    .map_err(|e| anyhow!(GitError::Unknown(e)))?
+            {
+                return Err(anyhow!("Commit {} is merged into {}", tip.id(), r_name));
+            }
+        }
+
+        reference
+            .set_target(parent, "autosave: undo")
+            .map_err(|e| anyhow!(GitError::Unknown(e)))
+            .context("Failed to update branch")?;
+        Ok(tip.id())
+    }
+
+    /// Find files deleted from working directory in snapshots on specified branch
+    ///
+    /// `pattern` is a path or glob relative to repository root.
+    /// Returns each deleted file with the latest snapshot containing it
+    pub fn find_deleted(
+        &self,
+        branch: impl AsRef<str>,
+        pattern: impl AsRef<str>,
+    ) -> Result<Vec<(PathBuf, Commit<'_>)>> {
+        let workdir = self
+            .0
+            .workdir()
+            .ok_or_else(|| anyhow!("Repository has no working directory"))?
+            .to_path_buf();
+        let spec = Pathspec::new([pattern.as_ref()]).map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let mut found: Vec<(PathBuf, Commit<'_>)> = Vec::new();
+        for commit in self.walk_branch(branch)? {
+            let commit = commit?;
+            let tree = commit.tree().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+            let list = match spec.match_tree(&tree, PathspecFlags::DEFAULT) {
+                Ok(l) => l,
+                Err(e) if e.code() == ErrorCode::NotFound => continue,
+                Err(e) => return Err(anyhow!(GitError::Unknown(e))),
+            };
+            for entry in list.entries() {
+                let path = bytes_to_path(entry);
+                if workdir.join(&path).symlink_metadata().is_err()
+                    && !found.iter().any(|(p, _)| p == &path)
+                {
+                    found.push((path, commit.clone()));
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// List snapshots on specified branch from oldest to newest
+    ///
+    /// Snapshots older than `since` are not listed
+    pub fn list_snapshots(
+        &self,
+        branch: impl AsRef<str>,
+        since: Option<SystemTime>,
+    ) -> Result<Vec<Commit<'_>>> {
+        let mut snapshots = Vec::new();
+        for commit in self.walk_branch(branch)? {
+            let commit = commit?;
+            if since.is_some_and(|s| from_git_time(commit.time()) < s) {
+                break;
+            }
+            snapshots.push(commit);
+        }
+        snapshots.reverse();
+        Ok(snapshots)
+    }
+
+    /// Annotate each line of file on specified branch with the snapshot which last changed it
+    pub fn blame(&self, branch: impl AsRef<str>, path: impl AsRef<Path>) -> Result<Vec<BlameLine>> {
+        let branch = self
+            .get_branch(&branch)?
+            .ok_or_else(|| anyhow!("Branch not found: {}", branch.as_ref()))?;
+        let commit = branch
+            .get()
+            .peel_to_commit()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let entry = commit
+            .tree()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?
+            .get_path(path.as_ref())
+            .map_err(|e| anyhow!(GitError::Unknown(e)))
+            .with_context(|| format!("File not found: {}", path.as_ref().display()))?;
+        let blob = self
+            .0
+            .find_blob(entry.id())
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let blame = self
+            .0
+            .blame_file(
+                path.as_ref(),
+                Some(
+                    BlameOptions::new()
+                        .newest_commit(commit.id())
+                        .first_parent(true),
+                ),
+            )
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+
+        let content = String::from_utf8_lossy(blob.content());
+        let mut lines = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let hunk = blame
+                .get_line(i + 1)
+                .ok_or_else(|| anyhow!("Failed to blame line {}", i + 1))?;
+            lines.push(BlameLine {
+                id: hunk.final_commit_id(),
+                time: hunk.final_signature().when(),
+                line: line.to_string(),
+            });
+        }
+        Ok(lines)
+    }
+
+    /// Find commit by revision string like commit id or branch name
+    pub fn find_commit_by_spec(&self, spec: impl AsRef<str>) -> Result<Commit<'_>> {
+        self.0
+            .revparse_single(spec.as_ref())
+            .and_then(|o| o.peel_to_commit())
+            .map_err(|e| anyhow!(GitError::Unknown(e)))
+            .with_context(|| format!("Commit not found: {}", spec.as_ref()))
+    }
+
+    /// Compare two snapshots with rename and copy detection
+    pub fn diff_snapshots(
+        &self,
+        from: impl AsRef<str>,
+        to: impl AsRef<str>,
+    ) -> Result<Vec<FileStat>> {
+        let from = self
+            .find_commit_by_spec(from)?
+            .tree()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let to = self
+            .find_commit_by_spec(to)?
+            .tree()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let mut diff = self
+            .0
+            .diff_tree_to_tree(
+                Some(&from),
+                Some(&to),
+                // Unmodified files are required to detect copies from them
+                Some(DiffOptions::new().include_unmodified(true)),
+            )
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        diff.find_similar(Some(
+            DiffFindOptions::new()
+                .renames(true)
+                .copies(true)
+                .copies_from_unmodified(true),
+        ))
+        .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+
+        let mut stats = Vec::new();
+        for (i, delta) in diff.deltas().enumerate() {
+            if delta.status() == Delta::Unmodified {
+                continue;
+            }
+            let (insertions, deletions) =
+                match Patch::from_diff(&diff, i).map_err(|e| anyhow!(GitError::Unknown(e)))? {
+                    Some(patch) => {
+                        let (_, insertions, deletions) = patch
+                            .line_stats()
+                            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+                        (insertions, deletions)
+                    }
+                    None => (0, 0),
+                };
+            stats.push(FileStat {
+                status: delta.status(),
+                old_path: delta.old_file().path().map(Path::to_path_buf),
+                new_path: delta.new_file().path().map(Path::to_path_buf),
+                insertions,
+                deletions,
+            });
+        }
+        Ok(stats)
+    }
 
-        self.change_head_ref(&current_head, "")
-            .context("Failed to restore HEAD reference")?;
+    /// Get labels of commit
+    pub fn labels(&self, oid: Oid) -> Result<Vec<String>> {
+        match self.0.find_note(Some(LABELS_REF), oid) {
+            Ok(note) => Ok(note
+                .message()
+                .unwrap_or_default()
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(|l| l.to_string())
+                .collect()),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(Vec::new()),
+            Err(e) => Err(anyhow!(GitError::Unknown(e))),
+        }
+    }
 
-        self.restore_index(current_index_entries)
-            .context("Failed to restore index entries")?;
+    /// Add label to commit specified by revision string
+    ///
+    /// Returns labeled commit id
+    pub fn add_label(&self, spec: impl AsRef<str>, label: impl AsRef<str>) -> Result<Oid> {
+        let label = label.as_ref().trim();
+        if label.is_empty() || label.contains('\n') {
+            return Err(anyhow!("Invalid label: {:?}", label));
+        }
+        let oid = self.find_commit_by_spec(spec)?.id();
+        let mut labels = self.labels(oid)?;
+        if labels.iter().any(|l| l == label) {
+            return Ok(oid);
+        }
+        labels.push(label.to_string());
+        let sig = self
+            .0
+            .signature()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        self.0
+            .note(&sig, &sig, Some(LABELS_REF), oid, &labels.join("\n"), true)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))
+            .context("Failed to write label")?;
+        Ok(oid)
+    }
+
+    /// Summarize snapshots on specified branch created in specified period
+    pub fn summarize(
+        &self,
+        branch: impl AsRef<str>,
+        since: SystemTime,
+        until: SystemTime,
+    ) -> Result<Summary> {
+        let mut summary = Summary::default();
+        let mut files = HashSet::new();
+        for commit in self.walk_branch(branch)? {
+            let commit = commit?;
+            let t = from_git_time(commit.time());
+            if t < since {
+                break;
+            }
+            if until < t {
+                continue;
+            }
+            let diff = self.get_parent_commit_diff(&commit, &mut DiffOptions::new())?;
+            let stats = diff.stats().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+            for delta in diff.deltas() {
+                if let Some(p) = delta.new_file().path().or(delta.old_file().path()) {
+                    files.insert(p.to_path_buf());
+                }
+            }
+            summary.commits += 1;
+            summary.insertions += stats.insertions();
+            summary.deletions += stats.deletions();
+        }
+        summary.files = files.len();
+        Ok(summary)
+    }
 
+    /// Write summary as a note on the tip of specified branch
+    pub fn write_summary_note(
+        &self,
+        branch: impl AsRef<str>,
+        message: impl AsRef<str>,
+    ) -> Result<()> {
+        let branch = self
+            .get_branch(&branch)?
+            .ok_or_else(|| anyhow!("Branch not found: {}", branch.as_ref()))?;
+        let oid = branch
+            .get()
+            .peel_to_commit()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?
+            .id();
+        let message = match self.0.find_note(Some(SUMMARY_REF), oid) {
+            Ok(note) => format!(
+                "{}\n{}",
+                note.message().unwrap_or_default(),
+                message.as_ref()
+            ),
+            Err(_) => message.as_ref().to_string(),
+        };
+        let sig = self
+            .0
+            .signature()
+            .map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        self.0
+            .note(&sig, &sig, Some(SUMMARY_REF), oid, &message, true)
+            .map_err(|e| anyhow!(GitError::Unknown(e)))
+            .context("Failed to write summary")?;
         Ok(())
     }
+
+    /// Whether saving is paused by git hooks
+    pub fn is_paused(&self) -> bool {
+        fs::metadata(self.0.path().join(PAUSE_FILE))
+            .and_then(|m| m.modified())
+            .is_ok_and(|t| t.elapsed().unwrap_or_default() < PAUSE_TIMEOUT)
+    }
+
+    /// Install hooks which pause saving during rebase and merge, and resume after
+    ///
+    /// Hooks call a separate script, and the call is inserted at the top of existing hooks
+    /// so that it runs even if they exit early. Fails without writing anything
+    /// if an existing hook is not a shell script. Returns paths of written files
+    pub fn install_hooks(&self) -> Result<Vec<PathBuf>> {
+        let config = self.0.config().map_err(|e| anyhow!(GitError::Unknown(e)))?;
+        let dir = match config.get_path("core.hooksPath") {
+            Ok(p) if p.is_absolute() => p,
+            Ok(p) => self.0.workdir().unwrap_or(self.0.path()).join(p),
+            Err(_) => self.0.path().join("hooks"),
+        };
+        let mut hooks = Vec::new();
+        for name in HOOKS {
+            let path = dir.join(name);
+            let script = match fs::read_to_string(&path) {
+                Ok(s) => s,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => "#!/bin/sh\n".to_string(),
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to read hook: {}", path.display()))
+                }
+            };
+            if script.contains(HOOK_SCRIPT) {
+                continue;
+            }
+            if !is_shell_script(&script) {
+                return Err(anyhow!(
+                    "Hook is not a shell script: {}\nhint: call {} {} from it",
+                    path.display(),
+                    dir.join(HOOK_SCRIPT).display(),
+                    name
+                ));
+            }
+            let call = format!(
+                "# Added by autosave\n\"$(dirname \"$0\")/{}\" {}\n",
+                HOOK_SCRIPT, name
+            );
+            let script = match script.split_once('\n') {
+                Some((shebang, rest)) if shebang.starts_with("#!") => {
+                    format!("{}\n{}{}", shebang, call, rest)
+                }
+                _ if script.starts_with("#!") => format!("{}\n{}", script, call),
+                _ => format!("{}{}", call, script),
+            };
+            hooks.push((path, script));
+        }
+
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create hooks directory: {}", dir.display()))?;
+        let script_path = dir.join(HOOK_SCRIPT);
+        let script = hook_script();
+        let mut written = Vec::new();
+        if fs::read_to_string(&script_path).ok().as_deref() != Some(script.as_str()) {
+            write_executable(&script_path, &script)?;
+            written.push(script_path);
+        }
+        for (path, script) in hooks {
+            write_executable(&path, &script)?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    /// Write save state for prompt integration
+    pub fn write_state(&self, state: &SaveState) -> Result<()> {
+        let last_save = state
+            .last_save
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs().to_string())
+            .unwrap_or("-".to_string());
+        let path = self.0.path().join(STATE_FILE);
+        write_atomic(
+            &path,
+            format!(
+                "{} {} {}\n",
+                STATE_VERSION,
+                state.status.as_str(),
+                last_save
+            ),
+        )
+        .with_context(|| format!("Failed to write state file: {}", path.display()))
+    }
+
+    /// Write progress of current save for `autosave status`
+    ///
+    /// Progress is informational, so failures are ignored
+    fn report_progress(&self, phase: SavePhase, done: usize, total: usize) {
+        let phase = match phase {
+            SavePhase::Scanning => "scanning",
+            SavePhase::Hashing => "hashing",
+            SavePhase::Committing => "committing",
+        };
+        let _ = write_atomic(
+            &self.0.path().join(PROGRESS_FILE),
+            format!("{} {} {} {}\n", STATE_VERSION, phase, done, total),
+        );
+    }
+
+    /// Read progress of a save running in the watcher
+    ///
+    /// Returns `None` if no save of whole working directory is running
+    pub fn read_progress(&self) -> Result<Option<SaveProgress>> {
+        let path = self.0.path().join(PROGRESS_FILE);
+        let s = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read progress file: {}", path.display()))
+            }
+        };
+        let invalid = || anyhow!("Invalid progress file: {}", path.display());
+        let fields: Vec<&str> = s
+            .strip_suffix('\n')
+            .ok_or_else(invalid)?
+            .split(' ')
+            .collect();
+        let [STATE_VERSION, phase, done, total] = fields[..] else {
+            return Err(invalid());
+        };
+        let phase = match phase {
+            "scanning" => SavePhase::Scanning,
+            "hashing" => SavePhase::Hashing,
+            "committing" => SavePhase::Committing,
+            _ => return Err(invalid()),
+        };
+        Ok(Some(SaveProgress {
+            phase,
+            done: done.parse().map_err(|_| invalid())?,
+            total: total.parse().map_err(|_| invalid())?,
+        }))
+    }
+
+    /// Read save state written by the watcher
+    ///
+    /// Returns `None` if no watcher has written the state
+    pub fn read_state(&self) -> Result<Option<SaveState>> {
+        let path = self.0.path().join(STATE_FILE);
+        let s = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read state file: {}", path.display()))
+            }
+        };
+        // Incomplete line is rejected in case the file was written by other means
+        let invalid = || anyhow!("Invalid state file: {}", path.display());
+        let mut fields = s.strip_suffix('\n').ok_or_else(invalid)?.split(' ');
+        match fields.next() {
+            Some(STATE_VERSION) => {}
+            Some(v) => {
+                return Err(anyhow!(
+                    "Unsupported state file version {}: {}",
+                    v,
+                    path.display()
+                ))
+            }
+            None => return Err(invalid()),
+        }
+        let status = fields
+            .next()
+            .and_then(SaveStatus::from_name)
+            .ok_or_else(invalid)?;
+        let last_save = fields
+            .next()
+            .and_then(|t| t.parse().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        Ok(Some(SaveState { status, last_save }))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::env;
+
+    /// Repository in a temporary directory with one commit, removed on drop
+    pub(crate) struct TestRepo {
+        pub(crate) dir: PathBuf,
+        pub(crate) repo: GitRepo,
+    }
+
+    impl TestRepo {
+        pub(crate) fn new(name: &str) -> Self {
+            let dir =
+                env::temp_dir().join(format!("autosave-test-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            let repo = Repository::init(&dir).unwrap();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+            fs::write(dir.join("a.txt"), "a\n").unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new("a.txt")).unwrap();
+            index.write().unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let sig = repo.signature().unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+                .unwrap();
+            drop(tree);
+            Self {
+                dir,
+                repo: GitRepo(repo),
+            }
+        }
+
+        fn save(&self, changed: Option<&HashSet<PathBuf>>) {
+            self.repo
+                .save(
+                    "autosave",
+                    "save",
+                    "merge",
+                    changed,
+                    &SaveOptions::default(),
+                )
+                .unwrap();
+        }
+
+        fn snapshot(&self) -> Commit<'_> {
+            self.repo
+                .walk_branch("autosave")
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap()
+        }
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[cfg(unix)]
+    fn non_utf8_name() -> PathBuf {
+        use std::os::unix::ffi::OsStrExt as _;
+        PathBuf::from(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saves_non_utf8_file_name() {
+        let t = TestRepo::new("save-non-utf8");
+        let name = non_utf8_name();
+        fs::write(t.dir.join(&name), "1\n").unwrap();
+        t.save(None);
+        let tree = t.snapshot().tree().unwrap();
+        assert!(tree.get_path(&name).is_ok());
+
+        // Incremental save gets absolute paths from the watcher
+        fs::write(t.dir.join(&name), "2\n").unwrap();
+        t.save(Some(&HashSet::from([t.dir.join(&name)])));
+        let history = t.repo.file_history("autosave", Some(&name)).unwrap();
+        assert_eq!(history.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restores_non_utf8_file_name() {
+        let t = TestRepo::new("restore-non-utf8");
+        let name = non_utf8_name();
+        fs::write(t.dir.join(&name), "1\n").unwrap();
+        t.save(None);
+        fs::remove_file(t.dir.join(&name)).unwrap();
+        let commit = t.snapshot();
+        t.repo
+            .restore_tree(&commit, std::slice::from_ref(&name), None, false, false)
+            .unwrap();
+        assert_eq!(fs::read(t.dir.join(&name)).unwrap(), b"1\n");
+    }
+
+    #[test]
+    fn redacts_saved_blobs() {
+        let t = TestRepo::new("redact");
+        fs::create_dir(t.dir.join("sub")).unwrap();
+        fs::write(t.dir.join(".env"), "KEY=secret\n").unwrap();
+        fs::write(t.dir.join("sub/b.env"), b"\xff\nKEY=secret\n").unwrap();
+        let options = SaveOptions {
+            redact: vec![RedactRule::new(
+                "**/*.env",
+                regex::bytes::Regex::new("KEY=.*").unwrap(),
+                "KEY=",
+            )],
+            ..Default::default()
+        };
+        t.repo
+            .save("autosave", "save", "merge", None, &options)
+            .unwrap();
+        let tree = t.snapshot().tree().unwrap();
+        let blob = |path: &str| {
+            let entry = tree.get_path(Path::new(path)).unwrap();
+            t.repo.0.find_blob(entry.id()).unwrap().content().to_vec()
+        };
+        assert_eq!(blob(".env"), b"KEY=\n");
+        assert_eq!(blob("sub/b.env"), b"\xff\nKEY=\n");
+        assert_eq!(fs::read(t.dir.join(".env")).unwrap(), b"KEY=secret\n");
+    }
+
+    #[test]
+    fn waits_for_lock_of_other_process() {
+        let t = TestRepo::new("lock");
+        fs::write(t.dir.join("b.txt"), "b\n").unwrap();
+        // Lock taken through another open file conflicts like a lock of another process
+        let held = t.repo.repo_lock().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let dir = t.dir.clone();
+        std::thread::spawn(move || {
+            let repo = GitRepo::new(dir).unwrap();
+            let result = repo.save("autosave", "save", "merge", None, &SaveOptions::default());
+            sender.send(result.is_ok()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(500)).is_err());
+        drop(held);
+        assert!(receiver.recv_timeout(Duration::from_secs(10)).unwrap());
+    }
+
+    #[test]
+    fn refuses_to_save_during_merge() {
+        let t = TestRepo::new("save-merge");
+        fs::write(t.dir.join(".git/MERGE_HEAD"), "").unwrap();
+        fs::write(t.dir.join("notes.txt"), "notes\n").unwrap();
+        let result = t
+            .repo
+            .save("autosave", "save", "merge", None, &SaveOptions::default());
+        assert!(is_in_progress_error(&result.unwrap_err()));
+        assert!(t.repo.get_branch("autosave").unwrap().is_none());
+    }
+
+    #[test]
+    fn lists_snapshots_after_fork_point() {
+        let t = TestRepo::new("fork-point");
+        fs::write(t.dir.join("a.txt"), "b\n").unwrap();
+        t.save(None);
+        fs::write(t.dir.join("a.txt"), "c\n").unwrap();
+        t.save(None);
+        let snapshots = t.repo.list_snapshots("autosave", None).unwrap();
+        let messages: Vec<_> = snapshots.iter().map(|c| c.summary().unwrap()).collect();
+        assert_eq!(messages, ["save", "save"]);
+        let history = t.repo.file_history("autosave", None).unwrap();
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn installs_hooks_before_existing_commands() {
+        let t = TestRepo::new("hooks");
+        let hooks = t.dir.join(".git/hooks");
+        fs::create_dir_all(&hooks).unwrap();
+        fs::write(
+            hooks.join("post-merge"),
+            "#!/usr/bin/env python3\nprint()\n",
+        )
+        .unwrap();
+        assert!(t.repo.install_hooks().is_err());
+        assert!(!hooks.join(HOOK_SCRIPT).exists());
+
+        fs::write(hooks.join("post-merge"), "#!/bin/bash\nexit 0\n").unwrap();
+        assert_eq!(t.repo.install_hooks().unwrap().len(), HOOKS.len() + 1);
+        let hook = fs::read_to_string(hooks.join("post-merge")).unwrap();
+        assert!(hook.starts_with("#!/bin/bash\n# Added by autosave\n"));
+        assert!(hook.ends_with("\nexit 0\n"));
+        assert!(t.repo.install_hooks().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_written_state() {
+        let t = TestRepo::new("state");
+        assert_eq!(t.repo.read_state().unwrap(), None);
+        let last_save = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for (status, last_save) in [
+            (SaveStatus::Saved, Some(last_save)),
+            (SaveStatus::DiskFull, Some(last_save)),
+            (SaveStatus::Dirty, None),
+        ] {
+            let state = SaveState { status, last_save };
+            t.repo.write_state(&state).unwrap();
+            assert_eq!(t.repo.read_state().unwrap(), Some(state));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_state() {
+        let t = TestRepo::new("invalid-state");
+        let path = t.dir.join(".git").join(STATE_FILE);
+        for contents in ["1 saved 100", "1 unknown 100\n", "2 saved 100\n", ""] {
+            fs::write(&path, contents).unwrap();
+            assert!(t.repo.read_state().is_err(), "{:?}", contents);
+        }
+    }
+
+    #[test]
+    fn reads_reported_progress() {
+        let t = TestRepo::new("progress");
+        assert_eq!(t.repo.read_progress().unwrap(), None);
+        t.repo.report_progress(SavePhase::Hashing, 3, 10);
+        let progress = t.repo.read_progress().unwrap().unwrap();
+        assert_eq!(progress.phase, SavePhase::Hashing);
+        assert_eq!((progress.done, progress.total), (3, 10));
+
+        let path = t.dir.join(".git").join(PROGRESS_FILE);
+        for contents in ["1 hashing 3\n", "1 hashing x 10\n", "1 waiting 3 10\n"] {
+            fs::write(&path, contents).unwrap();
+            assert!(t.repo.read_progress().is_err(), "{:?}", contents);
+        }
+    }
 }
@@ -0,0 +1,23 @@
+//! Watch file changes and save changed file to local Git repository as a commit automatically
+//!
+//! [`AutosaveEngine`] is the entry point for embedding autosave in other programs.
+//! Types in [`types`] and the engine methods follow semver;
+//! the other modules are used by the command line interface and may change in any release.
+//!
+//! Only Unix-like systems are supported.
+
+#[cfg(not(unix))]
+compile_error!("autosave supports only Unix-like systems");
+
+pub mod bisect;
+pub mod config;
+mod engine;
+pub mod git;
+mod notification;
+mod scheduler;
+pub mod time;
+pub mod types;
+mod verify;
+pub mod watcher;
+
+pub use engine::AutosaveEngine;
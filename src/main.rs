@@ -1,10 +1,23 @@
-mod config;
-mod git;
-mod watcher;
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use config::Config;
-use watcher::RepoWatcher;
+mod crypt;
+mod service;
+mod signal;
+use anyhow::{anyhow, Context as _, Result};
+use autosave::config::{Config, Settings, UserConfig};
+use autosave::git::GitRepo;
+use autosave::types::{SavePhase, SaveProgress, SaveStatus};
+use autosave::{bisect, time, watcher, AutosaveEngine};
+use clap::{Args, Parser, Subcommand};
+use git2::Delta;
+use log::{error, info, warn};
+use regex::Regex;
+use signal::{Signal, Signals};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Interval of checking that the watched path exists
+const PATH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Parser)]
 struct Cli {
@@ -14,26 +27,790 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Watch repository and save changes until interrupted
+    #[command(visible_alias = "watch")]
     Run {
-        path: Option<String>,
-        config: Option<String>,
+        path: Option<PathBuf>,
+        config: Option<PathBuf>,
+        /// Stay in the foreground; run never daemonizes, so this is the default
+        #[arg(long, hide = true)]
+        foreground: bool,
+        /// Watch path even if it is denied, e.g. home directory
+        #[arg(long)]
+        force: bool,
+        /// Only validate path, repository, configuration and watch, and print effective configuration
+        #[arg(long)]
+        check: bool,
+    },
+    /// Save working directory now
+    Save {
+        /// Only report files which would be saved or excluded
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Restore files from autosave snapshot
+    Restore {
+        /// Restore whole tree
+        #[arg(long, conflicts_with = "paths", required_unless_present = "paths")]
+        all: bool,
+        /// Point in time to restore, e.g. "10 minutes ago"
+        #[arg(long)]
+        at: String,
+        /// Directory to restore to instead of working directory
+        #[arg(long)]
+        to: Option<PathBuf>,
+        /// Only report files which would be overwritten or deleted
+        #[arg(long)]
+        dry_run: bool,
+        /// Overwrite files which have changes
+        #[arg(long)]
+        force: bool,
+        #[command(flatten)]
+        repo: RepoArgs,
+        /// Paths to restore
+        paths: Vec<PathBuf>,
+    },
+    /// List snapshots in which specified file (or any file) changed
+    Log {
+        /// File path
+        file: Option<PathBuf>,
+        /// Show patch of each snapshot
+        #[arg(short, long)]
+        patch: bool,
+        /// Only list snapshots with specified label
+        #[arg(long)]
+        label: Option<String>,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Label a snapshot, e.g. "tests-pass"
+    Label {
+        /// Snapshot to label, e.g. commit id
+        commit: String,
+        /// Label to add
+        label: String,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Export autosave history to bundle file
+    ///
+    /// The bundle is encrypted with gpg if `export_recipients` is configured
+    Export {
+        /// Bundle file path
+        file: PathBuf,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Import autosave history from bundle file, decrypted with gpg if encrypted
+    Import {
+        /// Bundle file path
+        file: PathBuf,
+        /// Branch to import to instead of configured branch
+        #[arg(long)]
+        branch: Option<String>,
+        /// Overwrite branch even if it has diverged
+        #[arg(long)]
+        force: bool,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Search lines added or removed by snapshots
+    Grep {
+        /// Regular expression to search
+        pattern: String,
+        /// Only search snapshots after specified time, e.g. "1 hour ago"
+        #[arg(long)]
+        since: Option<String>,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Drop the latest autosave commit
+    Undo {
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Annotate each line of file with the snapshot which last changed it
+    Blame {
+        /// File path
+        file: PathBuf,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Write snapshot to a separate directory
+    Checkout {
+        /// Snapshot to write, e.g. commit id
+        commit: String,
+        /// Directory to write to
+        #[arg(long)]
+        to: PathBuf,
+        /// Write even if the directory is not empty
+        #[arg(long)]
+        force: bool,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Compare two snapshots
+    Diff {
+        /// Old snapshot, e.g. commit id
+        #[arg(long)]
+        from: String,
+        /// New snapshot, defaults to the latest snapshot
+        #[arg(long)]
+        to: Option<String>,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Find the first snapshot where command fails
+    Bisect {
+        /// Command to run in each snapshot
+        #[arg(long)]
+        cmd: String,
+        /// Only search snapshots after specified time, e.g. "1 day ago"
+        #[arg(long)]
+        since: Option<String>,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Recover deleted files from snapshots
+    Recover {
+        /// Path or glob relative to repository root
+        pattern: String,
+        /// Only report files which would be recovered
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        repo: RepoArgs,
+    },
+    /// Print compact save status for shell prompts, e.g. "saved 12s"
+    ///
+    /// Prints nothing if the path is not a repository or is not watched
+    Prompt {
+        /// Repository path
+        #[arg(long, default_value = ".")]
+        path: PathBuf,
+    },
+    /// Show save status of repository
+    Status {
+        /// Print a single token for status bars, e.g. "✓ 35s" or "✗ err"
+        #[arg(long)]
+        short: bool,
+        /// Repository path
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
+    /// Manage systemd user service running autosave
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommands,
+    },
+    /// Manage git hooks which pause autosave during rebase and merge
+    Hooks {
+        #[command(subcommand)]
+        command: HooksCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServiceCommands {
+    /// Write systemd user unit and print the unit to enable for the repository
+    Install {
+        /// Repository path
+        #[arg(long, default_value = ".")]
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum HooksCommands {
+    /// Install hooks to the repository
+    Install {
+        #[command(flatten)]
+        repo: RepoArgs,
     },
 }
 
+/// Repository options shared by subcommands
+#[derive(Args)]
+struct RepoArgs {
+    /// Repository path
+    #[arg(long, default_value = ".")]
+    path: PathBuf,
+    /// Config file path
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+impl RepoArgs {
+    /// Open repository containing `--path`, which may be a subdirectory, and its configuration
+    fn open(&self) -> Result<(GitRepo, Config)> {
+        let conf = load_config(&self.path, self.config.as_ref())?;
+        let repo = GitRepo::discover(&self.path)?;
+        Ok((repo, conf))
+    }
+}
+
+/// Convert path relative to current directory, like file arguments, to path relative to
+/// the working directory root of repository
+///
+/// Path does not need to exist, e.g. a deleted file to restore
+fn repo_relative(repo: &GitRepo, path: &Path) -> Result<PathBuf> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("Repository has no working directory"))?;
+    let workdir = fs::canonicalize(workdir).context("Failed to get absolute path")?;
+    let path = env::current_dir()
+        .context("Failed to get current directory")?
+        .join(path);
+    // Existing part is resolved so that symbolic links like /tmp match the working directory
+    let (existing, rest) = path
+        .ancestors()
+        .find_map(|a| {
+            let rest = path.strip_prefix(a).ok()?;
+            fs::canonicalize(a).ok().map(|a| (a, rest))
+        })
+        .ok_or_else(|| anyhow!("Failed to get absolute path: {}", path.display()))?;
+    let path = if rest.as_os_str().is_empty() {
+        existing
+    } else {
+        existing.join(rest)
+    };
+    path.strip_prefix(&workdir)
+        .map(Path::to_path_buf)
+        .map_err(|_| anyhow!("Path is outside repository: {}", path.display()))
+}
+
+fn load_config(path: impl AsRef<Path>, config: Option<impl AsRef<Path>>) -> Result<Config> {
+    if let Some(cp) = config {
+        Config::from_file_path(cp)
+    } else {
+        Config::from_dir_path(path, ".autosave.toml")
+    }
+}
+
+/// Return path if it is root, home directory or in `deny_paths` of the user configuration
+///
+/// Watching these would commit a whole home tree by accident
+fn denied_path(path: &Path) -> Result<Option<PathBuf>> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Failed to get absolute path: {}", path.display()))?;
+    let denied = [PathBuf::from("/")]
+        .into_iter()
+        .chain(env::var_os("HOME").map(PathBuf::from))
+        .chain(UserConfig::load()?.deny_paths());
+    for d in denied {
+        if d.canonicalize().is_ok_and(|d| d == path) {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Read save status and time since the last save from the state file
+///
+/// Returns `None` if path is not a repository or has no valid state file
+fn read_status(path: &Path) -> Option<(SaveStatus, Option<Duration>)> {
+    let repo = GitRepo::discover(path).ok()?;
+    let state = repo.read_state().ok()??;
+    let status = if repo.is_paused() {
+        SaveStatus::Paused
+    } else {
+        state.status
+    };
+    let age = state
+        .last_save
+        .and_then(|t| SystemTime::now().duration_since(t).ok());
+    Some((status, age))
+}
+
+/// Format duration in its largest unit, e.g. "35s" or "12m"
+fn format_age(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Format save progress, e.g. "hashing 1200/50000"
+fn format_progress(progress: &SaveProgress) -> String {
+    match progress.phase {
+        SavePhase::Scanning => "scanning".to_string(),
+        SavePhase::Hashing => format!("hashing {}/{}", progress.done, progress.total),
+        SavePhase::Committing => "committing".to_string(),
+        _ => "saving".to_string(),
+    }
+}
+
 fn main() -> Result<()> {
     env_logger::init();
     let cli = Cli::parse();
     match cli.command {
-        Commands::Run { path, config } => {
-            let p = path.unwrap_or(".".to_string());
-            let conf = if let Some(cp) = config {
-                Config::from_file_path(cp)
+        Commands::Run {
+            path,
+            config,
+            force,
+            check,
+            ..
+        } => {
+            // Signals are blocked before the watcher threads are spawned
+            let signals = Signals::listen().context("Failed to block signals")?;
+            let p = path.unwrap_or(PathBuf::from("."));
+            let conf = load_config(&p, config.as_ref())?;
+            if !force {
+                if let Some(denied) = denied_path(&p)? {
+                    return Err(anyhow!(
+                        "Refusing to watch denied path: {} (use --force to watch anyway)",
+                        denied.display()
+                    ));
+                }
+            }
+            if check {
+                let effective = toml::to_string(&conf.effective()?)
+                    .context("Failed to format configuration")?;
+                let engine = AutosaveEngine::with_config(&p, conf)?;
+                watcher::check(engine.path())?;
+                println!("# Effective configuration of {}", engine.path().display());
+                print!("{}", effective);
+                return Ok(());
+            }
+            let mut missing_timeout = conf.missing_timeout()?;
+            let mut settings = Settings::try_from(&conf)?;
+            let mut engine = AutosaveEngine::with_config(&p, conf)?;
+            let mut watcher = Some(engine.watch()?);
+            if let Err(e) = service::notify("READY=1") {
+                warn!("Failed to notify readiness: {}", e);
+            }
+            // Watched path is checked periodically since it may be deleted or unmounted
+            let mut missing_since: Option<Instant> = None;
+            loop {
+                match signals
+                    .wait_timeout(PATH_CHECK_INTERVAL)
+                    .context("Failed to wait for signal")?
+                {
+                    Some(Signal::Shutdown) => break,
+                    Some(Signal::Reload) => {
+                        let reloaded = load_config(&p, config.as_ref()).and_then(|conf| {
+                            let timeout = conf.missing_timeout()?;
+                            let settings = Settings::try_from(&conf)?;
+                            Ok((timeout, settings, AutosaveEngine::with_config(&p, conf)?))
+                        });
+                        match reloaded {
+                            Ok((timeout, new_settings, new_engine)) => {
+                                missing_timeout = timeout;
+                                if new_settings != settings {
+                                    info!(
+                                        "Configuration changed, restarting watcher: {}",
+                                        p.display()
+                                    );
+                                    // Old watcher is stopped first not to save changes twice
+                                    drop(watcher.take());
+                                    settings = new_settings;
+                                    engine = new_engine;
+                                    watcher = Some(engine.watch()?);
+                                }
+                            }
+                            Err(e) => error!("Failed to reload configuration: {:#}", e),
+                        }
+                        continue;
+                    }
+                    None => {}
+                }
+                if engine.path().is_dir() {
+                    if missing_since.take().is_some() {
+                        info!("Watched path is back: {}", engine.path().display());
+                        watcher = Some(engine.watch()?);
+                    }
+                    continue;
+                }
+                if missing_since.is_none() {
+                    warn!("Watched path disappeared: {}", engine.path().display());
+                    watcher = None;
+                }
+                let since = *missing_since.get_or_insert_with(Instant::now);
+                if missing_timeout.is_some_and(|t| t <= since.elapsed()) {
+                    return Err(anyhow!(
+                        "Watched path is missing: {}",
+                        engine.path().display()
+                    ));
+                }
+            }
+            if let Err(e) = service::notify("STOPPING=1") {
+                warn!("Failed to notify stopping: {}", e);
+            }
+            drop(watcher);
+            Ok(())
+        }
+        Commands::Restore {
+            all: _,
+            at,
+            to,
+            dry_run,
+            force,
+            repo,
+            paths,
+        } => {
+            let (repo, conf) = repo.open()?;
+            let paths = paths
+                .iter()
+                .map(|p| repo_relative(&repo, p))
+                .collect::<Result<Vec<_>>>()?;
+            let at = time::parse_time(&at)?;
+            let commit = repo
+                .find_snapshot_at(conf.branch(), at)?
+                .ok_or_else(|| anyhow!("No snapshot found at specified time"))?;
+            println!(
+                "Snapshot {} ({})",
+                commit.id(),
+                time::format_git_time(commit.time())
+            );
+            let changes = repo.restore_tree(&commit, &paths, to.as_deref(), dry_run, force)?;
+            for (p, status) in changes {
+                let verb = match (dry_run, status) {
+                    (true, Delta::Deleted) => "Would delete",
+                    (true, _) => "Would overwrite",
+                    (false, Delta::Deleted) => "Deleted",
+                    (false, _) => "Overwritten",
+                };
+                println!("{}: {}", verb, p.display());
+            }
+            Ok(())
+        }
+        Commands::Log {
+            file,
+            patch,
+            label,
+            repo,
+        } => {
+            let (repo, conf) = repo.open()?;
+            let file = file.map(|f| repo_relative(&repo, &f)).transpose()?;
+            for change in repo.file_history(conf.branch(), file.as_deref())? {
+                let labels = repo.labels(change.id)?;
+                if label.as_ref().is_some_and(|l| !labels.contains(l)) {
+                    continue;
+                }
+                println!(
+                    "{} {} {} (+{} -{}){}",
+                    &change.id.to_string()[..7],
+                    time::format_git_time(change.time),
+                    change.message,
+                    change.insertions,
+                    change.deletions,
+                    if labels.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" [{}]", labels.join(", "))
+                    }
+                );
+                if patch {
+                    print!("{}", change.patch);
+                }
+            }
+            Ok(())
+        }
+        Commands::Save { dry_run, repo } => {
+            let (r, conf) = repo.open()?;
+            let workdir = r
+                .workdir()
+                .ok_or_else(|| anyhow!("Repository has no working directory"))?;
+            let dry_run = dry_run || conf.dry_run();
+            let engine = AutosaveEngine::with_config(workdir, conf)?;
+            if !dry_run {
+                return engine.save();
+            }
+            for file in engine.plan_save()? {
+                let mark = match file.status {
+                    Delta::Added => "A",
+                    Delta::Deleted => "D",
+                    Delta::Ignored => "!",
+                    _ => "M",
+                };
+                let redacted = if file.redacted { " (redacted)" } else { "" };
+                println!("{} {}{}", mark, file.path.display(), redacted);
+            }
+            Ok(())
+        }
+        Commands::Export { file, repo } => {
+            let (repo, conf) = repo.open()?;
+            let recipients = conf.export_recipients();
+            let oid = if recipients.is_empty() {
+                repo.export_bundle(conf.branch(), &file)?
             } else {
-                Config::from_dir_path(&p, ".autosave.toml")
+                crypt::encrypt_to(&file, &recipients, |w| {
+                    repo.export_bundle_to(conf.branch(), w)
+                })?
+            };
+            println!(
+                "Exported {} at {} to {}",
+                conf.branch(),
+                oid,
+                file.display()
+            );
+            Ok(())
+        }
+        Commands::Import {
+            file,
+            branch,
+            force,
+            repo,
+        } => {
+            let (repo, conf) = repo.open()?;
+            let branch = branch.unwrap_or(conf.branch());
+            let oid = if crypt::is_encrypted(&file)? {
+                crypt::decrypt_from(&file, |r| repo.import_bundle_from(r, &branch, force))?
+            } else {
+                repo.import_bundle(&file, &branch, force)?
+            };
+            println!("Imported {} at {} from {}", branch, oid, file.display());
+            Ok(())
+        }
+        Commands::Grep {
+            pattern,
+            since,
+            repo,
+        } => {
+            let (repo, conf) = repo.open()?;
+            let pattern = Regex::new(&pattern)?;
+            let since = since.map(time::parse_time).transpose()?;
+            for m in repo.grep_history(conf.branch(), &pattern, since)? {
+                println!(
+                    "{} {} {}: {}{}",
+                    &m.id.to_string()[..7],
+                    time::format_git_time(m.time),
+                    m.path.display(),
+                    if m.added { '+' } else { '-' },
+                    m.line
+                );
             }
-            .unwrap();
-            let _watcher = RepoWatcher::new(&p, conf).unwrap();
-            loop {}
+            Ok(())
         }
+        Commands::Undo { repo } => {
+            let (repo, conf) = repo.open()?;
+            let oid = repo.undo(conf.branch())?;
+            println!("Dropped {} from {}", oid, conf.branch());
+            println!("The commit remains in reflog until it expires and objects are pruned");
+            Ok(())
+        }
+        Commands::Recover {
+            pattern,
+            dry_run,
+            repo,
+        } => {
+            let (repo, conf) = repo.open()?;
+            let deleted = repo.find_deleted(conf.branch(), &pattern)?;
+            if deleted.is_empty() {
+                return Err(anyhow!("No deleted file found: {}", pattern));
+            }
+            for (path, commit) in deleted {
+                if !dry_run {
+                    repo.restore_tree(&commit, std::slice::from_ref(&path), None, false, false)?;
+                }
+                println!(
+                    "{} {} from {} ({})",
+                    if dry_run {
+                        "Would recover"
+                    } else {
+                        "Recovered"
+                    },
+                    path.display(),
+                    &commit.id().to_string()[..7],
+                    time::format_git_time(commit.time())
+                );
+            }
+            Ok(())
+        }
+        Commands::Bisect { cmd, since, repo } => {
+            let (repo, conf) = repo.open()?;
+            let since = since.map(time::parse_time).transpose()?;
+            match bisect::bisect(&repo, conf.branch(), &cmd, since)? {
+                Some(commit) => println!(
+                    "First failing snapshot: {} ({}) {}",
+                    commit.id(),
+                    time::format_git_time(commit.time()),
+                    commit.summary().unwrap_or_default()
+                ),
+                None => println!("Command succeeds on the latest snapshot"),
+            }
+            Ok(())
+        }
+        Commands::Blame { file, repo } => {
+            let (repo, conf) = repo.open()?;
+            let file = repo_relative(&repo, &file)?;
+            for l in repo.blame(conf.branch(), &file)? {
+                println!(
+                    "{} {} {}",
+                    &l.id.to_string()[..7],
+                    time::format_git_time(l.time),
+                    l.line
+                );
+            }
+            Ok(())
+        }
+        Commands::Diff { from, to, repo } => {
+            let (repo, conf) = repo.open()?;
+            let to = to.unwrap_or(conf.branch());
+            for stat in repo.diff_snapshots(&from, &to)? {
+                let status = match stat.status {
+                    Delta::Added => 'A',
+                    Delta::Deleted => 'D',
+                    Delta::Modified => 'M',
+                    Delta::Renamed => 'R',
+                    Delta::Copied => 'C',
+                    Delta::Typechange => 'T',
+                    _ => '?',
+                };
+                let old = stat.old_path.unwrap_or_default();
+                let new = stat.new_path.unwrap_or_default();
+                let path = if matches!(stat.status, Delta::Renamed | Delta::Copied) {
+                    format!("{} -> {}", old.display(), new.display())
+                } else {
+                    new.display().to_string()
+                };
+                println!(
+                    "{} {} (+{} -{})",
+                    status, path, stat.insertions, stat.deletions
+                );
+            }
+            Ok(())
+        }
+        Commands::Label {
+            commit,
+            label,
+            repo,
+        } => {
+            let (repo, _) = repo.open()?;
+            let oid = repo.add_label(&commit, &label)?;
+            println!("Labeled {} as {}", oid, label);
+            Ok(())
+        }
+        Commands::Checkout {
+            commit,
+            to,
+            force,
+            repo,
+        } => {
+            let (repo, _) = repo.open()?;
+            let commit = repo.find_commit_by_spec(&commit)?;
+            let to = Path::new(&to);
+            let not_empty = to
+                .read_dir()
+                .is_ok_and(|mut entries| entries.next().is_some());
+            if not_empty && !force {
+                return Err(anyhow!("Directory is not empty: {}", to.display()));
+            }
+            repo.restore_tree(&commit, &[], Some(to), false, force)?;
+            println!(
+                "Checked out {} ({}) to {}",
+                commit.id(),
+                time::format_git_time(commit.time()),
+                to.display()
+            );
+            Ok(())
+        }
+        Commands::Hooks {
+            command: HooksCommands::Install { repo },
+        } => {
+            let (repo, _) = repo.open()?;
+            let written = repo.install_hooks()?;
+            if written.is_empty() {
+                println!("Hooks are already installed");
+            }
+            for p in written {
+                println!("Installed: {}", p.display());
+            }
+            Ok(())
+        }
+        Commands::Prompt { path } => {
+            // Prompt is kept clean even if the state file is invalid
+            let Some((status, age)) = read_status(&path) else {
+                return Ok(());
+            };
+            match age {
+                Some(d) => println!("{} {}s", status.as_str(), d.as_secs()),
+                None => println!("{}", status.as_str()),
+            }
+            Ok(())
+        }
+        Commands::Status { short, path } => {
+            let status = read_status(&path);
+            let progress = GitRepo::discover(&path)
+                .ok()
+                .and_then(|r| r.read_progress().ok().flatten());
+            if short {
+                if let Some(p) = progress {
+                    println!("↻ {}", format_progress(&p));
+                    return Ok(());
+                }
+                let Some((status, age)) = status else {
+                    return Ok(());
+                };
+                let token = match (status, age) {
+                    (SaveStatus::Error, _) => "✗ err".to_string(),
+                    (SaveStatus::TimedOut | SaveStatus::DiskFull, _) => {
+                        format!("✗ {}", status.as_str())
+                    }
+                    (SaveStatus::Paused, _) => "‖ paused".to_string(),
+                    (SaveStatus::Saved, Some(d)) => format!("✓ {}", format_age(d)),
+                    (SaveStatus::Dirty, Some(d)) => format!("~ {}", format_age(d)),
+                    _ => format!("~ {}", status.as_str()),
+                };
+                println!("{}", token);
+                return Ok(());
+            }
+            let repo = GitRepo::discover(&path)?;
+            let workdir = repo.workdir().unwrap_or(&path);
+            let conf = load_config(workdir, None::<PathBuf>)?;
+            println!("Path:      {}", workdir.display());
+            println!("Branch:    {}", conf.branch());
+            match status {
+                Some((status, age)) => {
+                    println!("Status:    {}", status.as_str());
+                    match age {
+                        Some(d) => println!("Last save: {} ago", format_age(d)),
+                        None => println!("Last save: never"),
+                    }
+                }
+                None => println!("Status:    not watched"),
+            }
+            if let Some(p) = progress {
+                println!("Progress:  {}", format_progress(&p));
+            }
+            Ok(())
+        }
+        Commands::Service {
+            command: ServiceCommands::Install { path },
+        } => {
+            let (unit, instance) = service::install_unit(&path)?;
+            println!("Installed: {}", unit.display());
+            println!("Enable with: systemctl --user enable --now '{}'", instance);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn accepts_non_utf8_repository_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt as _;
+        let path = OsStr::from_bytes(b"caf\xe9");
+        let args = [
+            OsStr::new("autosave"),
+            OsStr::new("log"),
+            OsStr::new("--path"),
+            path,
+        ];
+        let Commands::Log { repo, .. } = Cli::try_parse_from(args).unwrap().command else {
+            panic!("log command is parsed");
+        };
+        assert_eq!(repo.path, Path::new(path));
     }
 }
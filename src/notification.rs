@@ -0,0 +1,21 @@
+use log::debug;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Show desktop notification with notify-send
+///
+/// Notifications are best effort, failures such as a missing notify-send are only logged
+pub fn send(summary: &str, body: &str) {
+    let spawned = Command::new("notify-send")
+        .args(["--app-name=autosave", summary, body])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    match spawned {
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => debug!("Failed to send notification: {}", e),
+    }
+}
@@ -0,0 +1,672 @@
+use crate::config::Settings;
+use crate::git::{is_disk_full_error, is_in_progress_error, is_lock_error, is_pause_file, GitRepo};
+use crate::notification;
+use crate::types::{SaveState, SaveStatus};
+use crate::verify::Verifier;
+use anyhow::Result;
+use git2::Delta;
+use log::{debug, error, info};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{
+    channel, sync_channel, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Maximum number of pending save requests
+const QUEUE_SIZE: usize = 64;
+/// Number of incremental saves before whole working directory is added again
+const FULL_SAVE_INTERVAL: usize = 100;
+/// Delay before the first retry of a save failed because the repository was locked,
+/// doubled on each retry
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(200);
+/// Number of retries before a locked repository is reported as a failure
+const MAX_LOCK_RETRIES: u32 = 8;
+/// File in working directory touched to request an immediate full save
+const TRIGGER_PATH: &str = ".git/autosave-trigger";
+/// Delay before retrying a save failed because the disk was full, doubled on each failure
+const DISK_FULL_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Maximum delay before retrying a save failed because the disk was full
+const MAX_DISK_FULL_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+/// Delay before retrying a failed save, doubled on each failure
+const ERROR_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay before retrying a failed save
+const MAX_ERROR_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Interval of checking whether a merge or rebase which blocked a save finished
+const IN_PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Interval of checking whether a timed out save finished
+const TIMED_OUT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Minimum interval between notifications of failing saves
+const ERROR_NOTIFICATION_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+
+/// Save request
+#[derive(Debug)]
+pub struct SaveRequest {
+    /// Changed paths which triggered the save
+    pub paths: Vec<PathBuf>,
+}
+
+/// Save counters
+#[derive(Debug, Default)]
+pub struct SaveMetrics {
+    /// Requests queued
+    pub queued: AtomicU64,
+    /// Requests dropped because the queue was full
+    pub dropped: AtomicU64,
+    /// Requests merged into another save
+    pub coalesced: AtomicU64,
+    /// Requests merged into the trailing save because of rate limit
+    pub throttled: AtomicU64,
+    /// Saves skipped by the stat pre-check
+    pub skipped: AtomicU64,
+    /// Saves succeeded
+    pub saved: AtomicU64,
+    /// Saves retried because the repository was locked
+    pub retried: AtomicU64,
+    /// Saves failed
+    pub failed: AtomicU64,
+    /// Saves which did not finish in time
+    pub timed_out: AtomicU64,
+}
+
+/// Save scheduler
+///
+/// Save requests are queued to a bounded channel and processed by a worker thread,
+/// so saves of a repository never run concurrently.
+/// The worker stops when all schedulers are dropped
+#[derive(Clone)]
+pub struct SaveScheduler {
+    sender: SyncSender<SaveRequest>,
+    metrics: Arc<SaveMetrics>,
+    /// Set when a request is dropped and its changed paths are unknown to the worker
+    overflowed: Arc<AtomicBool>,
+}
+
+impl SaveScheduler {
+    /// Start worker for repository in specified path
+    pub fn new(path: PathBuf, settings: Arc<Settings>) -> Self {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        let metrics = Arc::new(SaveMetrics::default());
+        let overflowed = Arc::new(AtomicBool::new(false));
+        let verifier = settings.verify_command.clone().map(|command| {
+            Verifier::new(
+                path.clone(),
+                settings.branch.clone(),
+                command,
+                settings.verify_interval,
+            )
+        });
+        let worker = Worker {
+            bucket: settings
+                .save_rate
+                .map(|rate| TokenBucket::new(rate / 60.0, settings.save_burst)),
+            settings,
+            metrics: metrics.clone(),
+            overflowed: overflowed.clone(),
+            state: None,
+            // Repository without state file has never been saved by autosave
+            first_save: GitRepo::new(&path)
+                .and_then(|r| r.read_state())
+                .is_ok_and(|s| s.is_none()),
+            verifier,
+            last_error_notification: None,
+            path,
+        };
+        thread::spawn(move || worker.run(receiver));
+        Self {
+            sender,
+            metrics,
+            overflowed,
+        }
+    }
+
+    /// Queue save request
+    ///
+    /// Request is dropped if the queue is full, since queued saves cover the change anyway
+    pub fn request(&self, request: SaveRequest) {
+        match self.sender.try_send(request) {
+            Ok(()) => {
+                self.metrics.queued.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Full(_)) => {
+                self.overflowed.store(true, Ordering::Relaxed);
+                self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("Save worker stopped");
+            }
+        }
+    }
+}
+
+/// Whether path is the file touched by editors to request an immediate save
+pub fn is_trigger(path: &Path) -> bool {
+    path.ends_with(TRIGGER_PATH)
+}
+
+/// Whether path is the repository itself or its configuration file
+fn is_repo_config(path: &Path) -> bool {
+    path.ends_with(".git") || path.ends_with(".git/config")
+}
+
+/// Whether path is in the git directory of root and does not control saving
+///
+/// Saves write to the git directory themselves, so these writes must not request saves
+pub fn is_git_internal(root: &Path, path: &Path) -> bool {
+    path.strip_prefix(root.join(".git")).is_ok_and(|rel| {
+        !rel.as_os_str().is_empty()
+            && !is_trigger(path)
+            && !is_repo_config(path)
+            && !is_pause_file(path)
+    })
+}
+
+/// Token bucket limiting save rate
+struct TokenBucket {
+    /// Tokens added per second
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            capacity: burst as f64,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    /// Take a token if available
+    fn try_take(&mut self) -> bool {
+        self.refill();
+        if 1.0 <= self.tokens {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time until a token becomes available
+    fn wait_time(&mut self) -> Duration {
+        self.refill();
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / self.rate)
+    }
+}
+
+/// Repository and result returned by a save
+type SaveResult = (GitRepo, anyhow::Result<()>);
+
+/// Outcome of `Worker::save`
+enum SaveOutcome {
+    Done(SaveResult),
+    /// Save did not finish in time and its result will be sent to the receiver
+    TimedOut(Receiver<SaveResult>),
+}
+
+struct Worker {
+    path: PathBuf,
+    settings: Arc<Settings>,
+    bucket: Option<TokenBucket>,
+    metrics: Arc<SaveMetrics>,
+    overflowed: Arc<AtomicBool>,
+    /// State last written to the state file
+    state: Option<SaveState>,
+    /// Whether no save has succeeded since the repository was first watched
+    first_save: bool,
+    verifier: Option<Verifier>,
+    /// Time a failing save was last notified
+    last_error_notification: Option<Instant>,
+}
+
+impl Worker {
+    /// Update state file if the state changed
+    ///
+    /// Writing only on change keeps the state file event from triggering another write
+    fn update_state(&mut self, repo: &GitRepo, status: SaveStatus, saved: bool) {
+        let last_save = if saved {
+            Some(SystemTime::now())
+        } else {
+            self.state.as_ref().and_then(|s| s.last_save)
+        };
+        let state = SaveState { status, last_save };
+        if self.state.as_ref() == Some(&state) {
+            return;
+        }
+        if let Err(e) = repo.write_state(&state) {
+            error!("{:#}", e);
+        }
+        self.state = Some(state);
+        if self.settings.notifications {
+            self.notify(status);
+        }
+    }
+
+    /// Request verification of the saved snapshot
+    ///
+    /// Verifier runs it once the verify interval has passed since the last verification
+    fn request_verify(&self) {
+        let Some(verifier) = self.verifier.as_ref() else {
+            return;
+        };
+        if !self.settings.dry_run {
+            verifier.request();
+        }
+    }
+
+    /// Show notification on the first save and when saving starts failing
+    ///
+    /// Failures are notified at most once per cooldown, since saves may fail repeatedly
+    fn notify(&mut self, status: SaveStatus) {
+        let path = self.path.display();
+        let failed = matches!(
+            status,
+            SaveStatus::Error | SaveStatus::DiskFull | SaveStatus::TimedOut
+        );
+        if failed {
+            if self
+                .last_error_notification
+                .is_some_and(|t| t.elapsed() < ERROR_NOTIFICATION_COOLDOWN)
+            {
+                return;
+            }
+            self.last_error_notification = Some(Instant::now());
+        }
+        match status {
+            SaveStatus::Saved if self.first_save => {
+                self.first_save = false;
+                notification::send("autosave", &format!("autosave is now protecting {}", path));
+            }
+            SaveStatus::Error => {
+                notification::send("autosave", &format!("Failed to save {}", path));
+            }
+            SaveStatus::DiskFull => {
+                notification::send("autosave", &format!("Disk is full, not saving {}", path));
+            }
+            SaveStatus::TimedOut => {
+                notification::send("autosave", &format!("Save timed out: {}", path));
+            }
+            _ => {}
+        }
+    }
+
+    /// Save repository, in a separate thread if timeout is configured
+    ///
+    /// libgit2 calls cannot be cancelled, so a timed out save keeps running in its thread
+    fn save(&self, repo: GitRepo, paths: Option<HashSet<PathBuf>>) -> SaveOutcome {
+        let settings = self.settings.clone();
+        let run = move |repo: GitRepo| {
+            if settings.dry_run {
+                let result = log_plan(&repo, &settings);
+                return (repo, result);
+            }
+            let result = repo.save(
+                &settings.branch,
+                &settings.commit_message,
+                &settings.merge_message,
+                paths.as_ref(),
+                &settings.save_options,
+            );
+            (repo, result)
+        };
+        let Some(timeout) = self.settings.save_timeout else {
+            return SaveOutcome::Done(run(repo));
+        };
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let _ = sender.send(run(repo));
+        });
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => SaveOutcome::Done(result),
+            Err(_) => SaveOutcome::TimedOut(receiver),
+        }
+    }
+
+    /// Wait for next request
+    ///
+    /// If `timeout` is specified, returns `Ok(None)` when it elapses
+    /// so that pending changes are saved after the rate limit or the retry backoff
+    fn next_request(
+        receiver: &Receiver<SaveRequest>,
+        timeout: Option<Duration>,
+    ) -> Result<Option<SaveRequest>, RecvTimeoutError> {
+        match timeout {
+            Some(timeout) => match receiver.recv_timeout(timeout) {
+                Ok(request) => Ok(Some(request)),
+                Err(RecvTimeoutError::Timeout) => Ok(None),
+                Err(e) => Err(e),
+            },
+            None => receiver
+                .recv()
+                .map(Some)
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        }
+    }
+
+    fn run(mut self, receiver: Receiver<SaveRequest>) {
+        // Repository is kept open between saves and reopened when configuration changes
+        let mut repo: Option<GitRepo> = None;
+        // Paths changed since the last successful save
+        let mut changed = HashSet::new();
+        // Whole working directory is added on the first save
+        let mut incremental_saves = FULL_SAVE_INTERVAL;
+        // Whether changes are pending because of rate limit
+        let mut throttled = false;
+        // Time to retry the save failed because the repository was locked
+        let mut retry_at: Option<Instant> = None;
+        let mut lock_failures = 0;
+        // Current backoff while the disk is full
+        let mut disk_full_delay: Option<Duration> = None;
+        // Current backoff while saves fail for other reasons
+        let mut error_delay: Option<Duration> = None;
+        // Save which timed out and may still be running
+        let mut timed_out: Option<(Receiver<SaveResult>, bool)> = None;
+        loop {
+            let throttle_wait = match self.bucket.as_mut() {
+                Some(bucket) if throttled => Some(bucket.wait_time()),
+                _ => None,
+            };
+            let retry_wait = retry_at.map(|t| t.saturating_duration_since(Instant::now()));
+            let timeout = throttle_wait.into_iter().chain(retry_wait).min();
+            let (request, closing) = match Self::next_request(&receiver, timeout) {
+                Ok(request) => (request, false),
+                // Pending changes are saved before the worker stops
+                Err(_) if timeout.is_some() => (None, true),
+                Err(_) => break,
+            };
+            let requests: Vec<_> = request.into_iter().chain(receiver.try_iter()).collect();
+            if throttled {
+                self.metrics
+                    .throttled
+                    .fetch_add(requests.len() as u64, Ordering::Relaxed);
+            } else {
+                self.metrics
+                    .coalesced
+                    .fetch_add(requests.len().saturating_sub(1) as u64, Ordering::Relaxed);
+            }
+            changed.extend(requests.into_iter().flat_map(|r| r.paths));
+            if self.overflowed.swap(false, Ordering::Relaxed) {
+                incremental_saves = FULL_SAVE_INTERVAL;
+            }
+            // Triggered save bypasses rate limit, retry backoff and schedule once, so the
+            // trigger is not kept with pending changes
+            let pending = changed.len();
+            changed.retain(|p| !is_trigger(p));
+            let triggered = changed.len() < pending;
+            if !closing && !triggered && retry_at.is_some_and(|t| Instant::now() < t) {
+                continue;
+            }
+            retry_at = None;
+            throttled = false;
+
+            // Next save is not started until the timed out save finishes
+            if let Some((job, full)) = timed_out.as_ref() {
+                match job.try_recv() {
+                    Ok((r, result)) => {
+                        debug!("Timed out save finished: {}", self.path.display());
+                        // Pending changes are kept since they may include changes after the save
+                        match result {
+                            Ok(()) => {
+                                self.metrics.saved.fetch_add(1, Ordering::Relaxed);
+                                if *full {
+                                    incremental_saves = 0;
+                                }
+                            }
+                            Err(e) => {
+                                error!("{:#}", e);
+                                self.metrics.failed.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        repo = Some(r);
+                        timed_out = None;
+                    }
+                    Err(TryRecvError::Empty) if closing => break,
+                    Err(TryRecvError::Empty) => {
+                        retry_at = Some(Instant::now() + TIMED_OUT_POLL_INTERVAL);
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => timed_out = None,
+                }
+            }
+
+            if changed.iter().any(|p| is_repo_config(p)) {
+                repo = None;
+            }
+            if repo.is_none() {
+                repo = GitRepo::new(&self.path).ok();
+            }
+            let Some(r) = repo.as_ref() else {
+                continue;
+            };
+            // Changes are kept and saved after the hooks resume saving
+            if r.is_paused() {
+                debug!("Saving is paused: {}", self.path.display());
+                self.update_state(r, SaveStatus::Paused, false);
+                continue;
+            }
+            // Changes are kept and saved when the scheduled window opens
+            let wait = self
+                .settings
+                .schedule
+                .as_ref()
+                .and_then(|s| s.wait_time(SystemTime::now()));
+            if let Some(wait) = wait.filter(|_| !triggered) {
+                if closing {
+                    break;
+                }
+                debug!(
+                    "Outside of schedule for {:?}: {}",
+                    wait,
+                    self.path.display()
+                );
+                self.update_state(r, SaveStatus::Dirty, false);
+                retry_at = Some(Instant::now() + wait);
+                continue;
+            }
+            let full = triggered || FULL_SAVE_INTERVAL <= incremental_saves;
+            if !full
+                && r.is_unchanged(&self.settings.branch, &changed)
+                    .unwrap_or(false)
+            {
+                self.metrics.skipped.fetch_add(1, Ordering::Relaxed);
+                self.update_state(r, SaveStatus::Saved, false);
+                changed.clear();
+                continue;
+            }
+            throttled =
+                !closing && !triggered && self.bucket.as_mut().is_some_and(|b| !b.try_take());
+            if throttled {
+                self.update_state(r, SaveStatus::Dirty, false);
+                continue;
+            }
+            let paths = (!full).then(|| changed.clone());
+            let Some(r) = repo.take() else {
+                continue;
+            };
+            let (r, result) = match self.save(r, paths) {
+                SaveOutcome::Done(result) => result,
+                SaveOutcome::TimedOut(job) => {
+                    error!("Save timed out: {}", self.path.display());
+                    self.metrics.timed_out.fetch_add(1, Ordering::Relaxed);
+                    if let Ok(r) = GitRepo::new(&self.path) {
+                        self.update_state(&r, SaveStatus::TimedOut, false);
+                    }
+                    timed_out = Some((job, full));
+                    retry_at = Some(Instant::now() + TIMED_OUT_POLL_INTERVAL);
+                    if closing {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            let r = repo.insert(r);
+            match result {
+                Ok(()) => {
+                    self.metrics.saved.fetch_add(1, Ordering::Relaxed);
+                    self.update_state(r, SaveStatus::Saved, true);
+                    changed.clear();
+                    incremental_saves = if full { 0 } else { incremental_saves + 1 };
+                    lock_failures = 0;
+                    disk_full_delay = None;
+                    error_delay = None;
+                    self.request_verify();
+                }
+                Err(e) if is_in_progress_error(&e) && !closing => {
+                    // Changes are kept, and the whole working directory is saved once the
+                    // operation finishes since it may change files without events
+                    debug!("{:#}: {}", e, self.path.display());
+                    self.update_state(r, SaveStatus::Paused, false);
+                    incremental_saves = FULL_SAVE_INTERVAL;
+                    retry_at = Some(Instant::now() + IN_PROGRESS_POLL_INTERVAL);
+                    lock_failures = 0;
+                }
+                Err(e) if is_lock_error(&e) && lock_failures < MAX_LOCK_RETRIES && !closing => {
+                    // Repository is likely used by a git command, so the save is retried later
+                    debug!("Repository is locked, retrying: {}", self.path.display());
+                    self.metrics.retried.fetch_add(1, Ordering::Relaxed);
+                    self.update_state(r, SaveStatus::Dirty, false);
+                    retry_at = Some(Instant::now() + LOCK_RETRY_DELAY * 2u32.pow(lock_failures));
+                    lock_failures += 1;
+                }
+                Err(e) if is_disk_full_error(&e) => {
+                    // Error is logged once until a save succeeds, not on every change
+                    let delay = match disk_full_delay {
+                        Some(d) => (d * 2).min(MAX_DISK_FULL_RETRY_DELAY),
+                        None => {
+                            error!("Disk is full, saves are backed off: {:#}", e);
+                            DISK_FULL_RETRY_DELAY
+                        }
+                    };
+                    debug!("Retrying save in {:?}: {}", delay, self.path.display());
+                    self.metrics.failed.fetch_add(1, Ordering::Relaxed);
+                    self.update_state(r, SaveStatus::DiskFull, false);
+                    disk_full_delay = Some(delay);
+                    retry_at = Some(Instant::now() + delay);
+                    lock_failures = 0;
+                }
+                Err(e) => {
+                    error!("{:#}", e);
+                    // Debug format keeps libgit2 error details for bug reports
+                    debug!("{:?}", e);
+                    self.metrics.failed.fetch_add(1, Ordering::Relaxed);
+                    self.update_state(r, SaveStatus::Error, false);
+                    // Error likely recurs, so changes are kept and saved after a backoff
+                    let delay = error_delay
+                        .map_or(ERROR_RETRY_DELAY, |d| (d * 2).min(MAX_ERROR_RETRY_DELAY));
+                    debug!("Retrying save in {:?}: {}", delay, self.path.display());
+                    error_delay = Some(delay);
+                    retry_at = Some(Instant::now() + delay);
+                    repo = None;
+                    lock_failures = 0;
+                }
+            }
+            debug!(
+                "Save metrics of {}: {:?}",
+                self.path.display(),
+                self.metrics
+            );
+            if closing {
+                break;
+            }
+        }
+    }
+}
+
+/// Log files which a save would commit instead of saving
+fn log_plan(repo: &GitRepo, settings: &Settings) -> Result<()> {
+    for file in repo.plan_save(&settings.branch, &settings.save_options)? {
+        match file.status {
+            Delta::Ignored => info!("Dry run: would exclude {} (ignored)", file.path.display()),
+            status => info!(
+                "Dry run: would save {} ({:?}{})",
+                file.path.display(),
+                status,
+                if file.redacted { ", redacted" } else { "" }
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::git::tests::TestRepo;
+    use std::fs;
+
+    /// Wait until condition holds, failing after a while
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn token_bucket_allows_burst() {
+        let mut bucket = TokenBucket::new(1.0, 3);
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+        let wait = bucket.wait_time();
+        assert!(Duration::from_millis(900) < wait && wait <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn token_bucket_refills() {
+        let mut bucket = TokenBucket::new(0.5, 2);
+        assert!(bucket.try_take() && bucket.try_take());
+        // Two seconds add one token at half a token per second
+        bucket.last -= Duration::from_secs(2);
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+        // Tokens do not exceed the burst
+        bucket.last -= Duration::from_secs(60);
+        assert!(bucket.try_take() && bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+
+    #[test]
+    fn saves_changes_after_merge() {
+        let t = TestRepo::new("scheduler-merge");
+        let merge_head = t.dir.join(".git/MERGE_HEAD");
+        fs::write(&merge_head, "").unwrap();
+        let settings = Arc::new(Settings::try_from(&Config::default()).unwrap());
+        let branch = settings.branch.clone();
+        let scheduler = SaveScheduler::new(t.dir.clone(), settings);
+        let notes = t.dir.join("notes.txt");
+        fs::write(&notes, "notes\n").unwrap();
+        scheduler.request(SaveRequest { paths: vec![notes] });
+        wait_until(|| {
+            t.repo
+                .read_state()
+                .is_ok_and(|s| s.is_some_and(|s| s.status == SaveStatus::Paused))
+        });
+        assert!(t.repo.list_snapshots(&branch, None).is_err());
+
+        // Changes are saved without another request once the merge is finished
+        fs::remove_file(&merge_head).unwrap();
+        wait_until(|| {
+            t.repo.list_snapshots(&branch, None).is_ok_and(|s| {
+                s.last()
+                    .is_some_and(|c| c.tree().unwrap().get_name("notes.txt").is_some())
+            })
+        });
+    }
+}
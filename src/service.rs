@@ -0,0 +1,137 @@
+use anyhow::{Context as _, Result};
+use autosave::config::config_home;
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+/// Name of the template unit, instantiated with the escaped repository path
+const UNIT_NAME: &str = "autosave@.service";
+
+/// Send state such as "READY=1" to systemd if supervised with `Type=notify`
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt as _;
+            use std::os::unix::net::SocketAddr;
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Abstract notify socket is not supported",
+            ));
+        }
+    } else {
+        socket.send_to(state.as_bytes(), &path)?;
+    }
+    Ok(())
+}
+
+/// Escape absolute path as systemd unit instance name, like `systemd-escape --path`
+fn escape_path(path: &Path) -> String {
+    let bytes = path.as_os_str().as_bytes();
+    let start = bytes.iter().position(|b| *b != b'/').unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|b| *b != b'/')
+        .map_or(start, |i| i + 1);
+    let s = &bytes[start..end];
+    if s.is_empty() {
+        return "-".to_string();
+    }
+    let mut escaped = String::new();
+    for (i, &b) in s.iter().enumerate() {
+        match b {
+            b'/' => escaped.push('-'),
+            b'.' if i == 0 => escaped.push_str("\\x2e"),
+            b if b.is_ascii_alphanumeric() || b == b':' || b == b'_' || b == b'.' => {
+                escaped.push(b as char)
+            }
+            b => escaped.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    escaped
+}
+
+/// Quote argument of an ExecStart command line
+///
+/// Specifiers (`%`) and environment variables (`$`) are expanded even in quotes,
+/// so they are escaped by doubling
+fn quote_exec_arg(arg: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        match c {
+            '"' | '\\' => quoted.push('\\'),
+            '%' | '$' => quoted.push(c),
+            _ => {}
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Write systemd user template unit and return its path and the instance for the repository
+pub fn install_unit(repo_path: impl AsRef<Path>) -> Result<(PathBuf, String)> {
+    let repo_path = fs::canonicalize(repo_path).context("Failed to get absolute path")?;
+    let dir = config_home()?.join("systemd/user");
+    let exe = env::current_exe().context("Failed to get executable path")?;
+    let unit = format!(
+        "[Unit]\n\
+         Description=Autosave %f\n\
+         \n\
+         [Service]\n\
+         Type=notify\n\
+         ExecStart={} run \"%f\"\n\
+         Restart=on-failure\n\
+         \n\
+         [Install]\n\
+         WantedBy=default.target\n",
+        quote_exec_arg(&exe.to_string_lossy())
+    );
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create unit directory: {}", dir.display()))?;
+    let unit_path = dir.join(UNIT_NAME);
+    fs::write(&unit_path, unit)
+        .with_context(|| format!("Failed to write unit: {}", unit_path.display()))?;
+    let instance = format!("autosave@{}.service", escape_path(&repo_path));
+    Ok((unit_path, instance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_path_like_systemd() {
+        assert_eq!(escape_path(Path::new("/")), "-");
+        assert_eq!(escape_path(Path::new("/home/user/src/")), "home-user-src");
+        assert_eq!(
+            escape_path(Path::new("/home/a b/x-y")),
+            "home-a\\x20b-x\\x2dy"
+        );
+        assert_eq!(escape_path(Path::new("/.hidden/a.b")), "\\x2ehidden-a.b");
+        let non_utf8 = std::ffi::OsStr::from_bytes(b"/caf\xe9");
+        assert_eq!(escape_path(Path::new(non_utf8)), "caf\\xe9");
+    }
+
+    #[test]
+    fn quotes_exec_arg() {
+        assert_eq!(quote_exec_arg("/usr/bin/autosave"), "\"/usr/bin/autosave\"");
+        assert_eq!(
+            quote_exec_arg("/home/a b/100%/$x/\"q\"\\"),
+            "\"/home/a b/100%%/$$x/\\\"q\\\"\\\\\""
+        );
+    }
+}
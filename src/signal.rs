@@ -0,0 +1,84 @@
+use log::error;
+use std::io;
+use std::mem::MaybeUninit;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// Signals handled by the runner
+const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Signal received by the runner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// SIGINT or SIGTERM
+    Shutdown,
+    /// SIGHUP
+    Reload,
+}
+
+/// Handled signals received by a thread waiting for them
+pub struct Signals(Receiver<Signal>);
+
+impl Signals {
+    /// Block handled signals and start receiving them
+    ///
+    /// Threads spawned afterwards inherit the mask, so call this before spawning any thread
+    pub fn listen() -> io::Result<Self> {
+        let set = signal_set()?;
+        match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+            0 => {}
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+        let (sender, receiver) = channel();
+        // sigwait is available on all Unix platforms, unlike sigtimedwait
+        thread::spawn(move || loop {
+            let mut sig = 0;
+            match unsafe { libc::sigwait(&set, &mut sig) } {
+                0 => {}
+                e => {
+                    error!(
+                        "Failed to wait for signal: {}",
+                        io::Error::from_raw_os_error(e)
+                    );
+                    break;
+                }
+            }
+            let signal = if sig == libc::SIGHUP {
+                Signal::Reload
+            } else {
+                Signal::Shutdown
+            };
+            if sender.send(signal).is_err() {
+                break;
+            }
+        });
+        Ok(Self(receiver))
+    }
+
+    /// Wait until one of handled signals is received
+    ///
+    /// Returns `None` if timeout elapsed without a signal
+    pub fn wait_timeout(&self, timeout: Duration) -> io::Result<Option<Signal>> {
+        match self.0.recv_timeout(timeout) {
+            Ok(signal) => Ok(Some(signal)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::other("Signal thread stopped")),
+        }
+    }
+}
+
+fn signal_set() -> io::Result<libc::sigset_t> {
+    let mut set = MaybeUninit::uninit();
+    unsafe {
+        if libc::sigemptyset(set.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for sig in SIGNALS {
+            if libc::sigaddset(set.as_mut_ptr(), sig) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(set.assume_init())
+    }
+}
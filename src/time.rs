@@ -0,0 +1,231 @@
+use anyhow::{anyhow, Result};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Parse point in time
+///
+/// Accepts relative time like "10 minutes ago" or timestamp like "2023-01-01 12:00:00" (UTC)
+pub fn parse_time(s: impl AsRef<str>) -> Result<SystemTime> {
+    let s = s.as_ref().trim();
+    if s == "now" {
+        return Ok(SystemTime::now());
+    }
+    if let Some(rel) = s.strip_suffix("ago") {
+        let d = humantime::parse_duration(rel.trim())
+            .map_err(|e| anyhow!("Invalid relative time: {}: {}", s, e))?;
+        return SystemTime::now()
+            .checked_sub(d)
+            .ok_or_else(|| anyhow!("Time out of range: {}", s));
+    }
+    humantime::parse_rfc3339_weak(s).map_err(|e| anyhow!("Invalid time: {}: {}", s, e))
+}
+
+/// Convert git time to system time
+pub fn from_git_time(t: git2::Time) -> SystemTime {
+    let secs = t.seconds();
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
+/// Format git time for display
+pub fn format_git_time(t: git2::Time) -> String {
+    humantime::format_rfc3339_seconds(from_git_time(t)).to_string()
+}
+
+/// Format date part of system time (UTC)
+pub fn format_date(t: SystemTime) -> String {
+    let mut s = humantime::format_rfc3339_seconds(t).to_string();
+    s.truncate(10);
+    s
+}
+
+/// Names of week days in the order of `tm_wday`
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Weekly window in local time in which saving is allowed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    /// Allowed days indexed from Sunday
+    days: [bool; 7],
+    /// Start of window in minutes since midnight
+    start: u32,
+    /// End of window in minutes since midnight, before start if the window spans midnight
+    end: u32,
+}
+
+impl Schedule {
+    /// Parse days like `["mon-fri", "sun"]` and hours like "09:00-19:00"
+    ///
+    /// All days and all hours are allowed if not specified
+    pub fn parse(days: Option<&[String]>, hours: Option<&str>) -> Result<Self> {
+        let days = match days {
+            Some(days) => {
+                let mut allowed = [false; 7];
+                for d in days {
+                    let (first, last) = d.split_once('-').unwrap_or((d, d));
+                    let first = parse_day(first)?;
+                    let last = parse_day(last)?;
+                    // Range may wrap around the week, e.g. "fri-mon"
+                    let mut i = first;
+                    loop {
+                        allowed[i] = true;
+                        if i == last {
+                            break;
+                        }
+                        i = (i + 1) % 7;
+                    }
+                }
+                allowed
+            }
+            None => [true; 7],
+        };
+        let (start, end) = match hours {
+            Some(h) => {
+                let (start, end) = h
+                    .split_once('-')
+                    .ok_or_else(|| anyhow!("Invalid hours: {}", h))?;
+                (parse_clock(start)?, parse_clock(end)?)
+            }
+            None => (0, MINUTES_PER_DAY),
+        };
+        if start == MINUTES_PER_DAY || start == end {
+            return Err(anyhow!("Empty hours: {}", hours.unwrap_or_default()));
+        }
+        Ok(Self { days, start, end })
+    }
+
+    /// Time until saving is allowed, `None` if allowed at `t`
+    pub fn wait_time(&self, t: SystemTime) -> Option<Duration> {
+        let now = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as libc::time_t;
+        let tm = local_time(now);
+        let minute = (tm.tm_hour * 60 + tm.tm_min) as u32;
+        let wday = tm.tm_wday as usize;
+        let allowed = if self.start < self.end {
+            self.days[wday] && self.start <= minute && minute < self.end
+        } else {
+            // Window after midnight belongs to the previous day
+            (self.days[wday] && self.start <= minute)
+                || (self.days[(wday + 6) % 7] && minute < self.end)
+        };
+        if allowed {
+            return None;
+        }
+        for day in 0..8 {
+            let mut start = tm;
+            start.tm_mday += day;
+            start.tm_hour = (self.start / 60) as i32;
+            start.tm_min = (self.start % 60) as i32;
+            start.tm_sec = 0;
+            start.tm_isdst = -1;
+            // mktime normalizes the date and sets week day
+            let secs = unsafe { libc::mktime(&mut start) };
+            if now < secs && self.days[start.tm_wday as usize] {
+                return Some(Duration::from_secs((secs - now) as u64));
+            }
+        }
+        // Unreachable with a valid schedule, checked again a day later
+        Some(Duration::from_secs(u64::from(MINUTES_PER_DAY) * 60))
+    }
+}
+
+fn parse_day(s: &str) -> Result<usize> {
+    let s = s.trim().to_ascii_lowercase();
+    DAY_NAMES
+        .iter()
+        .position(|d| s.starts_with(d))
+        .ok_or_else(|| anyhow!("Invalid day: {}", s))
+}
+
+/// Parse "HH:MM" as minutes since midnight, accepting "24:00"
+fn parse_clock(s: &str) -> Result<u32> {
+    let invalid = || anyhow!("Invalid time of day: {}", s);
+    let (h, m) = s.trim().split_once(':').ok_or_else(invalid)?;
+    let h: u32 = h.parse().map_err(|_| invalid())?;
+    let m: u32 = m.parse().map_err(|_| invalid())?;
+    let minutes = h * 60 + m;
+    if 60 <= m || MINUTES_PER_DAY < minutes {
+        return Err(invalid());
+    }
+    Ok(minutes)
+}
+
+fn local_time(secs: libc::time_t) -> libc::tm {
+    let mut tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    tm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Local time at the date, which tests choose away from daylight saving changes
+    fn local(year: i32, month: i32, day: i32, hour: i32, min: i32) -> SystemTime {
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = year - 1900;
+        tm.tm_mon = month - 1;
+        tm.tm_mday = day;
+        tm.tm_hour = hour;
+        tm.tm_min = min;
+        tm.tm_isdst = -1;
+        let secs = unsafe { libc::mktime(&mut tm) };
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    }
+
+    fn hours(h: u64) -> Option<Duration> {
+        Some(Duration::from_secs(h * 60 * 60))
+    }
+
+    fn days(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_days_and_hours() {
+        let s = Schedule::parse(Some(&days(&["mon-wed", "Friday"])), Some("09:30-24:00")).unwrap();
+        assert_eq!(s.days, [false, true, true, true, false, true, false]);
+        assert_eq!((s.start, s.end), (9 * 60 + 30, MINUTES_PER_DAY));
+
+        let s = Schedule::parse(Some(&days(&["fri-mon"])), None).unwrap();
+        assert_eq!(s.days, [true, true, false, false, false, true, true]);
+        assert_eq!((s.start, s.end), (0, MINUTES_PER_DAY));
+    }
+
+    #[test]
+    fn rejects_invalid_schedule() {
+        assert!(Schedule::parse(Some(&days(&["someday"])), None).is_err());
+        for hours in [
+            "9-17",
+            "09:00",
+            "09:60-10:00",
+            "09:00-24:01",
+            "10:00-10:00",
+            "24:00-01:00",
+        ] {
+            assert!(Schedule::parse(None, Some(hours)).is_err(), "{}", hours);
+        }
+    }
+
+    #[test]
+    fn waits_for_window() {
+        // 2024-01-01 is a Monday
+        let s = Schedule::parse(Some(&days(&["mon-fri"])), Some("09:00-17:00")).unwrap();
+        assert_eq!(s.wait_time(local(2024, 1, 1, 8, 0)), hours(1));
+        assert_eq!(s.wait_time(local(2024, 1, 1, 10, 0)), None);
+        assert_eq!(s.wait_time(local(2024, 1, 1, 17, 0)), hours(16));
+        // Friday evening waits for Monday morning
+        assert_eq!(s.wait_time(local(2024, 1, 5, 18, 0)), hours(63));
+    }
+
+    #[test]
+    fn waits_for_window_over_midnight() {
+        let s = Schedule::parse(Some(&days(&["mon"])), Some("22:00-06:00")).unwrap();
+        // Early Tuesday belongs to the window of Monday
+        assert_eq!(s.wait_time(local(2024, 1, 2, 3, 0)), None);
+        assert_eq!(s.wait_time(local(2024, 1, 1, 3, 0)), hours(19));
+        assert_eq!(s.wait_time(local(2024, 1, 2, 7, 0)), hours(6 * 24 + 15));
+    }
+}
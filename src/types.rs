@@ -0,0 +1,223 @@
+//! Data types returned by the library API
+//!
+//! These types are part of the public API and changed only with a semver-incompatible release.
+//! They are non-exhaustive, so fields and variants may be added in compatible releases
+
+use crate::watcher::RepoWatcher;
+use git2::{Delta, Oid};
+use regex::bytes::Regex;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Watch of a repository started by `AutosaveEngine::watch`
+///
+/// Changes are saved until this is dropped
+#[must_use = "watching stops when the watch is dropped"]
+#[non_exhaustive]
+pub struct Watch {
+    _watcher: RepoWatcher,
+}
+
+impl Watch {
+    pub(crate) fn new(watcher: RepoWatcher) -> Self {
+        Self { _watcher: watcher }
+    }
+}
+
+/// Snapshot (autosave commit)
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Snapshot {
+    pub id: Oid,
+    pub time: git2::Time,
+    pub message: String,
+}
+
+/// Snapshot in which a file changed
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct FileChange {
+    pub id: Oid,
+    pub time: git2::Time,
+    pub message: String,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub patch: String,
+}
+
+/// Line added or removed in a snapshot
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct LineMatch {
+    pub id: Oid,
+    pub time: git2::Time,
+    pub path: PathBuf,
+    pub added: bool,
+    pub line: String,
+}
+
+/// Line annotated with the snapshot which last changed it
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct BlameLine {
+    pub id: Oid,
+    pub time: git2::Time,
+    pub line: String,
+}
+
+/// Change statistics of a file between two snapshots
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct FileStat {
+    pub status: Delta,
+    pub old_path: Option<PathBuf>,
+    pub new_path: Option<PathBuf>,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+/// File which a save would commit
+///
+/// `Delta::Ignored` means the file is excluded by gitignore
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct PlannedFile {
+    pub path: PathBuf,
+    pub status: Delta,
+    /// Contents are rewritten by redaction rules
+    pub redacted: bool,
+}
+
+/// Summary of snapshots in a period
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct Summary {
+    pub commits: usize,
+    pub files: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+/// Options for saving
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct SaveOptions {
+    /// Do not recurse into untracked directories and write refreshed stat cache to index
+    /// in status checks, for repositories where status is slow
+    pub large_repo: bool,
+    /// Rules rewriting file contents in snapshots
+    pub redact: Vec<RedactRule>,
+    /// Append list of changed files to commit message
+    pub commit_summary: bool,
+    /// Amend the latest autosave commit instead of committing when this many were made
+    /// in the last hour
+    pub max_commits_per_hour: Option<u32>,
+}
+
+/// Rule rewriting matches of pattern in files matching glob before they are saved
+///
+/// Working tree files are left as is
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RedactRule {
+    pub glob: String,
+    pub pattern: Regex,
+    pub replace: String,
+}
+
+impl RedactRule {
+    /// Create rule replacing matches of `pattern` with `replace` in files matching `glob`
+    pub fn new(glob: impl Into<String>, pattern: Regex, replace: impl Into<String>) -> Self {
+        Self {
+            glob: glob.into(),
+            pattern,
+            replace: replace.into(),
+        }
+    }
+}
+
+impl PartialEq for RedactRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.glob == other.glob
+            && self.pattern.as_str() == other.pattern.as_str()
+            && self.replace == other.replace
+    }
+}
+
+/// Phase of a save in progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SavePhase {
+    /// Looking for changed files
+    Scanning,
+    /// Writing changed files to the object database
+    Hashing,
+    /// Writing tree and commit
+    Committing,
+}
+
+/// Progress of a save of whole working directory
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct SaveProgress {
+    pub phase: SavePhase,
+    /// Number of files hashed
+    pub done: usize,
+    /// Number of changed files, estimated in large repository mode
+    pub total: usize,
+}
+
+/// Save status reported by the watcher
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SaveStatus {
+    /// All changes are saved
+    Saved,
+    /// Changes are waiting to be saved
+    Dirty,
+    /// Saving is paused by git hooks
+    Paused,
+    /// The last save failed
+    Error,
+    /// The last save did not finish in time
+    TimedOut,
+    /// Saving is backed off because the disk is full
+    DiskFull,
+}
+
+impl SaveStatus {
+    const ALL: [Self; 6] = [
+        Self::Saved,
+        Self::Dirty,
+        Self::Paused,
+        Self::Error,
+        Self::TimedOut,
+        Self::DiskFull,
+    ];
+
+    /// Name of the status in the state file and status output, e.g. "disk-full"
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Saved => "saved",
+            Self::Dirty => "dirty",
+            Self::Paused => "paused",
+            Self::Error => "error",
+            Self::TimedOut => "timeout",
+            Self::DiskFull => "disk-full",
+        }
+    }
+
+    /// Status of name returned by `as_str`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|s| s.as_str() == name)
+    }
+}
+
+/// Save state of a repository maintained by the watcher
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SaveState {
+    pub status: SaveStatus,
+    /// Time of the last successful save by the watcher
+    pub last_save: Option<SystemTime>,
+}
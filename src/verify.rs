@@ -0,0 +1,60 @@
+use crate::bisect::run_on_snapshot;
+use crate::git::GitRepo;
+use anyhow::Result;
+use log::error;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Label of snapshots on which verify command succeeded
+pub const PASS_LABEL: &str = "verify-pass";
+/// Label of snapshots on which verify command failed
+pub const FAIL_LABEL: &str = "verify-fail";
+
+/// Runs verify command on the latest snapshot in a separate thread
+///
+/// The thread stops when the verifier is dropped
+pub struct Verifier {
+    sender: SyncSender<()>,
+}
+
+impl Verifier {
+    /// Start thread verifying snapshots on specified branch, at most once per `interval`
+    pub fn new(path: PathBuf, branch: String, command: String, interval: Duration) -> Self {
+        // One request is kept while verifying or waiting for the interval,
+        // so the latest snapshot is verified next
+        let (sender, receiver) = sync_channel(1);
+        thread::spawn(move || {
+            let mut last: Option<Instant> = None;
+            for () in receiver {
+                if let Some(t) = last {
+                    thread::sleep(interval.saturating_sub(t.elapsed()));
+                }
+                last = Some(Instant::now());
+                if let Err(e) = verify(&path, &branch, &command) {
+                    error!("Failed to verify snapshot: {:#}", e);
+                }
+            }
+        });
+        Self { sender }
+    }
+
+    /// Request verification of the latest snapshot
+    pub fn request(&self) {
+        let _ = self.sender.try_send(());
+    }
+}
+
+/// Run command on the latest snapshot and label it with the result
+fn verify(path: &Path, branch: &str, command: &str) -> Result<()> {
+    let repo = GitRepo::new(path)?;
+    let commit = repo.find_commit_by_spec(branch)?;
+    let label = if run_on_snapshot(&repo, &commit, command)? {
+        PASS_LABEL
+    } else {
+        FAIL_LABEL
+    };
+    repo.add_label(commit.id().to_string(), label)?;
+    Ok(())
+}
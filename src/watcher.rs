@@ -1,40 +1,249 @@
-use crate::config::Config;
-use crate::git::GitRepo;
-use anyhow::{Context as _, Result};
-use log::{error, info};
-use notify::{recommended_watcher, RecommendedWatcher, RecursiveMode, Watcher};
-use std::path::Path;
+use crate::config::{Config, SaveTrigger, Settings};
+use crate::git::{is_beyond_symlink, GitRepo};
+use crate::scheduler::{is_git_internal, is_trigger, SaveRequest, SaveScheduler};
+use crate::time::format_date;
+use anyhow::{anyhow, Context as _, Result};
+use log::{error, info, warn};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{recommended_watcher, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// Repository watcher
 ///
 /// This object watches file changes and perform auto save when file is saved
-pub struct RepoWatcher(RecommendedWatcher);
+pub struct RepoWatcher {
+    /// Directory watch thread stops when this is dropped
+    _watcher: Arc<Mutex<RecommendedWatcher>>,
+    /// Daily summary thread stops when this is dropped
+    _summary_stop: Option<Sender<()>>,
+}
 
 impl RepoWatcher {
     /// Create new watcher in specified path, specified configuration
-    pub fn new(path: impl ToString, conf: Config) -> Result<Self> {
-        let p = path.to_string();
-        let branch = conf.branch();
-        let commit_message = conf.commit_message();
-        let merge_message = conf.merge_message();
-        let mut watcher =
-            recommended_watcher(move |result: Result<notify::Event, notify::Error>| {
-                if let Ok(ev) = result {
-                    if ev.kind.is_create() || ev.kind.is_modify() || ev.kind.is_remove() {
-                        if let Ok(repo) = GitRepo::new(&p) {
-                            if let Err(e) = repo.save(&branch, &commit_message, &merge_message) {
-                                error!("{}", e);
-                            }
+    pub fn new(path: impl AsRef<Path>, conf: Config) -> Result<Self> {
+        Self::with_settings(path, Arc::new(Settings::try_from(&conf)?))
+    }
+
+    /// Create new watcher in specified path, specified effective settings
+    pub fn with_settings(path: impl AsRef<Path>, settings: Arc<Settings>) -> Result<Self> {
+        // Event paths are made absolute to match the repository working directory
+        let p = fs::canonicalize(path).context("Failed to get absolute path")?;
+        validate_path(&p)?;
+        let scheduler = SaveScheduler::new(p.clone(), settings.clone());
+        let root = p.clone();
+        let trigger = settings.trigger;
+        // Other backends do not report closing of files
+        if trigger == SaveTrigger::CloseWrite && !cfg!(target_os = "linux") {
+            warn!("close_write trigger is not supported on this platform, using modify");
+        }
+        // Watches cannot be added in the event handler, which runs in the watcher thread
+        let (new_dirs, new_dirs_receiver) = mpsc::channel::<PathBuf>();
+        let watcher = recommended_watcher(move |result: Result<notify::Event, notify::Error>| {
+            if let Ok(mut ev) = result {
+                // Saves themselves write to the git directory
+                ev.paths.retain(|path| {
+                    !is_beyond_symlink(&root, path) && !is_git_internal(&root, path)
+                });
+                if is_dir_added(&ev.kind) {
+                    for path in &ev.paths {
+                        if path.symlink_metadata().is_ok_and(|m| m.is_dir()) {
+                            let _ = new_dirs.send(path.clone());
                         }
                     }
                 }
-            })
-            .context("Watcher create error")?;
-        let p = path.to_string();
-        watcher
-            .watch(Path::new(&p), RecursiveMode::Recursive)
+                // Trigger file is usually touched, which only changes its metadata
+                let triggered = matches!(ev.kind, EventKind::Create(_) | EventKind::Modify(_))
+                    && ev.paths.iter().any(|p| is_trigger(p));
+                let change = match trigger {
+                    SaveTrigger::CloseWrite if cfg!(target_os = "linux") => {
+                        is_close_write(&ev.kind)
+                    }
+                    _ => is_content_change(&ev.kind),
+                };
+                if (change || triggered) && !ev.paths.is_empty() {
+                    scheduler.request(SaveRequest { paths: ev.paths });
+                }
+            }
+        })
+        .context("Watcher create error")?;
+        let watcher = Arc::new(Mutex::new(watcher));
+        watch_tree(&mut *watcher.lock().unwrap_or_else(|e| e.into_inner()), &p)
             .context("Watch start error")?;
-        info!("Start watching: {}", &p);
-        Ok(Self(watcher))
+        spawn_dir_watch(Arc::downgrade(&watcher), new_dirs_receiver);
+        info!("Start watching: {}", p.display());
+        let summary_stop = settings
+            .daily_summary
+            .then(|| spawn_daily_summary(p.clone(), settings));
+        Ok(Self {
+            _watcher: watcher,
+            _summary_stop: summary_stop,
+        })
+    }
+}
+
+/// Check that path can be watched, without saving
+///
+/// The watches are created and dropped, which fails e.g. when inotify watches run out
+pub fn check(path: impl AsRef<Path>) -> Result<()> {
+    let p = fs::canonicalize(path).context("Failed to get absolute path")?;
+    validate_path(&p)?;
+    let mut watcher = recommended_watcher(|_: Result<notify::Event, notify::Error>| {})
+        .context("Watcher create error")?;
+    watch_tree(&mut watcher, &p).context("Watch start error")?;
+    Ok(())
+}
+
+/// Watch directory and its subdirectories without following symbolic links
+///
+/// Recursive watches of notify follow links, which may watch whole file systems
+/// outside the repository, so every directory is watched non-recursively instead
+fn watch_tree(watcher: &mut impl Watcher, dir: &Path) -> Result<()> {
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        // Directory may be removed while walking
+        if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+            if dir.symlink_metadata().is_err() {
+                continue;
+            }
+            return Err(e.into());
+        }
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries.flatten() {
+            // File type of entry is not resolved through symbolic links
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Watch directories created or moved into the repository
+///
+/// The thread stops when the watcher is dropped
+fn spawn_dir_watch(watcher: Weak<Mutex<RecommendedWatcher>>, new_dirs: Receiver<PathBuf>) {
+    thread::spawn(move || {
+        for dir in new_dirs {
+            let Some(watcher) = watcher.upgrade() else {
+                break;
+            };
+            let mut watcher = watcher.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = watch_tree(&mut *watcher, &dir) {
+                error!("Failed to watch {}: {:#}", dir.display(), e);
+            }
+        }
+    });
+}
+
+/// Check that path is the working directory root of a repository,
+/// since saves open the repository in the path
+fn validate_path(path: &Path) -> Result<()> {
+    if !path.is_dir() {
+        return Err(anyhow!("Not a directory: {}", path.display()));
+    }
+    let repo = GitRepo::new(path)
+        .with_context(|| format!("Not a git repository root: {}", path.display()))?;
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("Repository has no working directory: {}", path.display()))?;
+    if fs::canonicalize(workdir).ok().as_deref() != Some(path) {
+        return Err(anyhow!(
+            "Not a working directory root: {} (root is {})",
+            path.display(),
+            workdir.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Whether event may change file contents
+///
+/// Metadata changes (access time, permissions and so on) are ignored,
+/// executable bit changes are saved with the next change of the file
+fn is_content_change(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether event may add a directory which needs its own watch
+fn is_dir_added(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Any))
+    )
+}
+
+/// Whether event finishes a change of file contents
+///
+/// Creation is saved when the created file is closed, and new symbolic links
+/// are saved with the next change
+fn is_close_write(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Modify(ModifyKind::Name(_))
+            | EventKind::Remove(_)
+    )
+}
+
+/// Write summary of the previous day at every midnight (UTC)
+///
+/// The thread stops when the returned sender is dropped
+fn spawn_daily_summary(path: PathBuf, settings: Arc<Settings>) -> Sender<()> {
+    let (stop, stopped) = mpsc::channel();
+    thread::spawn(move || loop {
+        let now = SystemTime::now();
+        let days = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / DAY.as_secs();
+        let midnight = UNIX_EPOCH + DAY * (days as u32 + 1);
+        let wait = midnight.duration_since(now).unwrap_or_default();
+        if stopped.recv_timeout(wait) != Err(RecvTimeoutError::Timeout) {
+            break;
+        }
+        if let Err(e) = write_daily_summary(&path, &settings.branch, midnight - DAY, midnight) {
+            error!("Failed to write daily summary: {:#}", e);
+        }
+    });
+    stop
+}
+
+fn write_daily_summary(
+    path: &Path,
+    branch: &str,
+    since: SystemTime,
+    until: SystemTime,
+) -> Result<()> {
+    let repo = GitRepo::new(path)?;
+    let summary = repo.summarize(branch, since, until)?;
+    if summary.commits == 0 {
+        return Ok(());
     }
+    repo.write_summary_note(
+        branch,
+        format!(
+            "Summary of {}\nCommits: {}\nFiles touched: {}\nChurn: +{} -{}",
+            format_date(since),
+            summary.commits,
+            summary.files,
+            summary.insertions,
+            summary.deletions
+        ),
+    )?;
+    info!("Daily summary written: {}", path.display());
+    Ok(())
 }